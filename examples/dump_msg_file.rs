@@ -3,7 +3,7 @@ use nomcfb::oxcmsg::{Message, Recipient, RecipientOwned, Attachment, AttachmentO
 use std::fs::File;
 
 fn main() -> BoxResult<()> {
-	let args: Vec<String> = std::env::args().into_iter().collect();
+	let args: Vec<String> = std::env::args().collect();
 	if args.len() < 2 {
 		eprintln!("Usage: {} [filename]", &args[0]);
 		std::process::exit(1);
@@ -0,0 +1,147 @@
+use crate::cfb::{CompoundFile, HeaderValidation, MetadataParser, HEADER_SIZE};
+use crate::dir;
+use crate::error::CfbResult;
+use crate::stream::StreamReader;
+
+use std::future::poll_fn;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+
+use futures_io::{AsyncRead, AsyncSeek};
+
+async fn read_exact<R: AsyncRead + Unpin>(reader: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+	while !buf.is_empty() {
+		match poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, buf)).await {
+			Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+			Ok(len) => buf = &mut buf[len..],
+			Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+			Err(err) => return Err(err),
+		}
+	}
+	Ok(())
+}
+
+async fn seek<R: AsyncSeek + Unpin>(reader: &mut R, pos: SeekFrom) -> io::Result<u64> {
+	poll_fn(|cx| Pin::new(&mut *reader).poll_seek(cx, pos)).await
+}
+
+// Feeds the sectors asked for by the shared metadata parser from reader.
+async fn read_metadata<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, validation: HeaderValidation) -> CfbResult<MetadataParser> {
+	let mut buf = vec![0u8; HEADER_SIZE];
+	read_exact(reader, &mut buf).await?;
+	let file_size = seek(reader, SeekFrom::End(0)).await?;
+	let mut parser = MetadataParser::new(&buf, file_size, validation)?;
+	buf.resize(parser.sector_size(), 0);
+	while let Some(offset) = parser.next_sector()? {
+		seek(reader, SeekFrom::Start(offset)).await?;
+		read_exact(reader, &mut buf).await?;
+		parser.push(&buf)?;
+	}
+	Ok(parser)
+}
+
+impl CompoundFile {
+	// Async counterpart of parse_metadata_from_reader.
+	pub async fn parse_metadata_from_async_reader<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> CfbResult<Self> {
+		Self::parse_metadata_from_async_reader_with(reader, HeaderValidation::Strict).await
+	}
+
+	pub async fn parse_metadata_from_async_reader_with<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, validation: HeaderValidation) -> CfbResult<Self> {
+		read_metadata(reader, validation).await?.compound_file()
+	}
+
+	// Async counterpart of parse_from_reader.
+	pub async fn parse_from_async_reader<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R) -> CfbResult<Self> {
+		Self::parse_from_async_reader_with(reader, HeaderValidation::Strict).await
+	}
+
+	pub async fn parse_from_async_reader_with<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, validation: HeaderValidation) -> CfbResult<Self> {
+		let parser = read_metadata(reader, validation).await?;
+		// the compound file planning the reads is dropped before them, since
+		// holding its Rc directory entries across an await would keep the
+		// returned future from being Send
+		let stream_reads = parser.compound_file()?.stream_reads()?;
+		let mut data = Vec::with_capacity(stream_reads.len());
+		for reads in stream_reads {
+			let mut bytes = vec![0u8; reads.size];
+			let mut position = 0;
+			for (offset, len) in reads.runs {
+				seek(reader, SeekFrom::Start(offset)).await?;
+				read_exact(reader, &mut bytes[position..position + len]).await?;
+				position += len;
+			}
+			data.push((reads.id, bytes));
+		}
+
+		let cfb = parser.compound_file()?;
+		for (id, bytes) in data {
+			*cfb.dirs[id].data.borrow_mut() = bytes;
+		}
+		Ok(cfb)
+	}
+
+	// Opens a stream for reading on demand from an async reader. The returned
+	// StreamReader implements AsyncRead and AsyncSeek.
+	pub fn async_stream_reader<R: AsyncRead + AsyncSeek + Unpin>(&self, reader: R, entry: &dir::DirectoryEntry) -> CfbResult<StreamReader<R>> {
+		let (chunk_size, offsets) = self.stream_offsets(entry)?;
+		Ok(StreamReader::new(reader, chunk_size, offsets, entry.stream_size))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::CompoundFileBuilder;
+
+	use futures::executor::block_on;
+	use futures::io::{AsyncReadExt, Cursor};
+
+	fn assert_send<T: Send>(_: &T) {}
+
+	fn sample(mut builder: CompoundFileBuilder, large: usize) -> Vec<u8> {
+		builder.create_storage("/Storage").unwrap()
+			.create_stream("/Storage/Small", (0..3000).map(|i| i as u8).collect()).unwrap()
+			.create_stream("/Storage/Empty", Vec::new()).unwrap()
+			.create_stream("/Large", (0..large).map(|i| (i / 7) as u8).collect()).unwrap();
+		let mut cursor = std::io::Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	#[test]
+	fn async_parse_matches_sync_parse() {
+		// the last file needs DIFAT sectors beyond the 109 header entries
+		for bytes in [sample(CompoundFileBuilder::new_v3(), 70000), sample(CompoundFileBuilder::new_v4(), 70000), sample(CompoundFileBuilder::new_v3(), 7_500_000)] {
+			let expected = CompoundFile::parse_from_reader(&mut std::io::Cursor::new(&bytes)).unwrap();
+			let mut reader = Cursor::new(&bytes);
+			let future = CompoundFile::parse_from_async_reader(&mut reader);
+			assert_send(&future);
+			assert_eq!(block_on(future).unwrap(), expected);
+
+			let expected = CompoundFile::parse_metadata_from_reader(&mut std::io::Cursor::new(&bytes)).unwrap();
+			let parsed = block_on(CompoundFile::parse_metadata_from_async_reader(&mut Cursor::new(&bytes))).unwrap();
+			assert_eq!(parsed, expected);
+		}
+	}
+
+	#[test]
+	fn async_stream_reader_reads_streams() {
+		let bytes = sample(CompoundFileBuilder::new_v3(), 70000);
+		let expected = CompoundFile::parse_from_reader(&mut std::io::Cursor::new(&bytes)).unwrap();
+		let cfb = block_on(CompoundFile::parse_metadata_from_async_reader(&mut Cursor::new(&bytes))).unwrap();
+		for path in ["/Storage/Small", "/Storage/Empty", "/Large"] {
+			let mut reader = cfb.async_stream_reader(Cursor::new(&bytes), &cfb.open_stream(path).unwrap()).unwrap();
+			let mut data = Vec::new();
+			block_on(reader.read_to_end(&mut data)).unwrap();
+			assert_eq!(data, *expected.open_stream(path).unwrap().data.borrow());
+		}
+	}
+
+	#[test]
+	fn async_parse_reports_truncated_files() {
+		let bytes = sample(CompoundFileBuilder::new_v3(), 70000);
+		let truncated = &bytes[..bytes.len() - 1000];
+		let result = block_on(CompoundFile::parse_from_async_reader(&mut Cursor::new(truncated)));
+		assert_eq!(result.unwrap_err().to_string(), CompoundFile::parse_from_reader(&mut std::io::Cursor::new(truncated)).unwrap_err().to_string());
+	}
+}
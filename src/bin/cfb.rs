@@ -0,0 +1,214 @@
+use nomcfb::cfb::{CompoundFile, HeaderValidation};
+use nomcfb::check;
+use nomcfb::dir::{self, DirectoryEntry};
+use nomcfb::error::{BoxError, BoxResult, CfbError};
+use nomcfb::extract::{escape_name, unescape_name};
+use nomcfb::walk::WalkOrder;
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const USAGE: &str = "\
+Usage: cfb <command> <file> [arguments]
+
+Commands:
+	info <file>                   header summary
+	ls <file> [path]              entries of a storage with sizes and CLSIDs
+	tree <file>                   the whole directory tree
+	cat <file> <path>             write a stream to stdout
+	extract <file> <dir>          write every storage as a directory and every stream as a file
+	check <file>                  validate the file structure
+	hexdump <file> --sector <n>   dump a sector
+
+Names are written and read with control characters and other characters not
+allowed in file names escaped as %XX, e.g. /%05SummaryInformation.";
+
+fn usage() -> ! {
+	eprintln!("{}", USAGE);
+	std::process::exit(2);
+}
+
+fn type_name(object_type: u8) -> &'static str {
+	match object_type {
+		dir::OBJECT_UNKNOWN => "unknown",
+		dir::OBJECT_STORAGE => "storage",
+		dir::OBJECT_STREAM => "stream",
+		dir::OBJECT_ROOT_STORAGE => "root",
+		_ => "invalid",
+	}
+}
+
+fn print_entry<W: Write>(out: &mut W, entry: &DirectoryEntry, name: &str) -> io::Result<()> {
+	let size = if entry.object_type == dir::OBJECT_STREAM { entry.stream_size.to_string() } else { "-".to_string() };
+	writeln!(out, "{:<7} {:>10} {} {}", type_name(entry.object_type), size, dir::format_clsid(&entry.clsid), name)
+}
+
+// Unescapes every component of a path given on the command line.
+fn entry_path(path: &str) -> BoxResult<String> {
+	let components = path.split('/').map(unescape_name).collect::<Result<Vec<String>, _>>()?;
+	Ok(components.join("/"))
+}
+
+fn hexdump<W: Write>(out: &mut W, offset: u64, bytes: &[u8]) -> io::Result<()> {
+	for (i, line) in bytes.chunks(16).enumerate() {
+		let mut hex = String::new();
+		for (j, byte) in line.iter().enumerate() {
+			hex.push_str(if j == 8 { "  " } else { " " });
+			hex.push_str(&format!("{:02x}", byte));
+		}
+		let ascii: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+		writeln!(out, "{:08x} {:<49}  |{}|", offset + (i * 16) as u64, hex, ascii)?;
+	}
+	Ok(())
+}
+
+// Whether err comes from writing to a closed pipe, e.g. when piped into head.
+fn is_broken_pipe(err: &BoxError) -> bool {
+	let io_err = match err.downcast_ref::<CfbError>() {
+		Some(CfbError::Io(err)) => Some(err),
+		_ => err.downcast_ref::<io::Error>(),
+	};
+	io_err.is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+fn run<W: Write>(args: &[String], out: &mut W) -> BoxResult<()> {
+	if args.len() < 3 {
+		usage();
+	}
+	let (command, file_name, rest) = (args[1].as_str(), &args[2], &args[3..]);
+	let mut file = File::open(file_name)?;
+
+	match (command, rest) {
+		("info", []) => {
+			let cfb = CompoundFile::parse_metadata_from_reader_with(&mut file, HeaderValidation::Lenient)?;
+			writeln!(out, "{}", cfb.header)?;
+		}
+		("ls", [] | [_]) => {
+			let cfb = CompoundFile::parse_metadata_from_reader_with(&mut file, HeaderValidation::Lenient)?;
+			let path = entry_path(rest.first().map_or("/", String::as_str))?;
+			let storage = cfb.open_storage(&path)?;
+			for (name, entry) in storage.children.borrow().iter() {
+				print_entry(out, entry, &escape_name(name.as_str()))?;
+			}
+		}
+		("tree", []) => {
+			let cfb = CompoundFile::parse_metadata_from_reader_with(&mut file, HeaderValidation::Lenient)?;
+			for (_, depth, entry) in cfb.walk(WalkOrder::PreOrder) {
+				let name = if depth == 0 { "/".to_string() } else { escape_name(&entry.name) };
+				print_entry(out, entry, &format!("{}{}", "  ".repeat(depth), name))?;
+			}
+		}
+		("cat", [path]) => {
+			let cfb = CompoundFile::parse_metadata_from_reader_with(&mut file, HeaderValidation::Lenient)?;
+			cfb.copy_stream_to(&mut file, &entry_path(path)?, out, |_, _| {})?;
+		}
+		("extract", [dir]) => {
+			let cfb = CompoundFile::parse_from_reader_with(&mut file, HeaderValidation::Lenient)?;
+			cfb.extract(Path::new(dir))?;
+		}
+		("check", []) => {
+			let problems = check::check_reader(&mut file)?;
+			if problems.is_empty() {
+				writeln!(out, "no problems found")?;
+			} else {
+				for problem in &problems {
+					writeln!(out, "{}", problem)?;
+				}
+				out.flush()?;
+				return Err(format!("{} problem{} found", problems.len(), if problems.len() == 1 { "" } else { "s" }).into());
+			}
+		}
+		("hexdump", [flag, sector]) if flag == "--sector" => {
+			let cfb = CompoundFile::parse_metadata_from_reader_with(&mut file, HeaderValidation::Lenient)?;
+			let sector: u32 = sector.parse()?;
+			let offset = cfb.header.sector_offset(sector) as u64;
+			let mut bytes = Vec::with_capacity(cfb.header.sector_size());
+			file.seek(SeekFrom::Start(offset))?;
+			file.take(cfb.header.sector_size() as u64).read_to_end(&mut bytes)?;
+			if bytes.is_empty() {
+				return Err(format!("sector {} lies outside of the file", sector).into());
+			}
+			hexdump(out, offset, &bytes)?;
+		}
+		_ => usage(),
+	}
+
+	out.flush()?;
+	Ok(())
+}
+
+fn main() {
+	let args: Vec<String> = std::env::args().collect();
+	let stdout = io::stdout();
+	match run(&args, &mut io::BufWriter::new(stdout.lock())) {
+		Err(err) if !is_broken_pipe(&err) => {
+			eprintln!("cfb: {}", err);
+			std::process::exit(1);
+		}
+		_ => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use nomcfb::builder::CompoundFileBuilder;
+
+	use std::fs;
+	use std::path::PathBuf;
+
+	// Writes a small compound file to a file of its own and returns its path.
+	fn sample_file(name: &str) -> PathBuf {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap();
+		builder.create_stream("/Storage/\u{5}Summary", b"summary".to_vec()).unwrap();
+		builder.create_stream("/Large", vec![7; 5000]).unwrap();
+		let path = std::env::temp_dir().join(format!("nomcfb-cli-{}-{}.cfb", std::process::id(), name));
+		builder.build().unwrap().write_to(&mut File::create(&path).unwrap()).unwrap();
+		path
+	}
+
+	fn run_command(command: &[&str], path: &Path) -> (BoxResult<()>, String) {
+		let mut args = vec!["cfb".to_string(), command[0].to_string(), path.to_string_lossy().into_owned()];
+		args.extend(command[1..].iter().map(ToString::to_string));
+		let mut out = Vec::new();
+		let result = run(&args, &mut out);
+		(result, String::from_utf8(out).unwrap())
+	}
+
+	#[test]
+	fn tree_and_cat() {
+		let path = sample_file("tree");
+		let (result, tree) = run_command(&["tree"], &path);
+		result.unwrap();
+		let lines: Vec<&str> = tree.lines().collect();
+		assert_eq!(lines.len(), 4);
+		assert!(lines[0].starts_with("root") && lines[0].ends_with(" /"));
+		assert!(lines[1].starts_with("stream") && lines[1].contains(" 5000 ") && lines[1].ends_with("  Large"));
+		assert!(lines[3].ends_with("    %05Summary"));
+
+		let (result, data) = run_command(&["cat", "/Storage/%05Summary"], &path);
+		result.unwrap();
+		assert_eq!(data, "summary");
+		fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn check_reports_problems() {
+		let path = sample_file("check");
+		let (result, output) = run_command(&["check"], &path);
+		result.unwrap();
+		assert_eq!(output, "no problems found\n");
+
+		// point the header at a second FAT sector that does not exist
+		let mut bytes = fs::read(&path).unwrap();
+		bytes[44..48].copy_from_slice(&2u32.to_le_bytes());
+		fs::write(&path, bytes).unwrap();
+		let (result, output) = run_command(&["check"], &path);
+		assert_eq!(result.unwrap_err().to_string(), "1 problem found");
+		assert_eq!(output, "header field fat_sectors is 2, expected 1\n");
+		fs::remove_file(path).unwrap();
+	}
+}
@@ -0,0 +1,145 @@
+use crate::cfb::{self, CompoundFile, HeaderValidation, MetadataParser, MiniStream, HEADER_SIZE};
+use crate::dir::DirectoryEntry;
+use crate::error::{CfbError, CfbResult};
+
+use std::borrow::Cow;
+use std::io;
+
+// A compound file parsed from a byte slice, such as a memory-mapped file.
+// Only the metadata is parsed up front; streams are returned as slices of the
+// input where their sectors are contiguous and copied only where they are not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BorrowedCompoundFile<'a> {
+	pub cfb: CompoundFile,
+	data: &'a [u8],
+	mini_stream: MiniStream,
+}
+
+fn slice(data: &[u8], offset: u64, len: usize) -> CfbResult<&[u8]> {
+	usize::try_from(offset).ok()
+		.and_then(|offset| data.get(offset..offset.checked_add(len)?))
+		.ok_or_else(|| CfbError::Io(io::ErrorKind::UnexpectedEof.into()))
+}
+
+impl<'a> BorrowedCompoundFile<'a> {
+	pub fn parse(data: &'a [u8]) -> CfbResult<Self> {
+		Self::parse_with(data, HeaderValidation::Strict)
+	}
+
+	pub fn parse_with(data: &'a [u8], validation: HeaderValidation) -> CfbResult<Self> {
+		// sectors are parsed where they lie in data rather than read into a buffer
+		let mut parser = MetadataParser::new(slice(data, 0, HEADER_SIZE)?, data.len() as u64, validation)?;
+		while let Some(offset) = parser.next_sector()? {
+			parser.push(slice(data, offset, parser.sector_size())?)?;
+		}
+		let cfb = parser.compound_file()?;
+		let mini_stream = MiniStream::new(&cfb)?;
+		Ok(Self { cfb, data, mini_stream })
+	}
+
+	pub fn data(&self) -> &'a [u8] {
+		self.data
+	}
+
+	pub fn stream(&self, entry: &DirectoryEntry) -> CfbResult<Cow<'a, [u8]>> {
+		let (chunk_size, offsets) = self.cfb.stream_offsets_with(&self.mini_stream, entry)?;
+		let size = usize::try_from(entry.stream_size).map_err(|_| CfbError::StreamTooLarge { name: entry.name.clone() })?;
+		match cfb::read_runs(chunk_size, &offsets, size).as_slice() {
+			[] => Ok(Cow::Borrowed(&[])),
+			&[(offset, len)] => Ok(Cow::Borrowed(slice(self.data, offset, len)?)),
+			runs => {
+				let mut bytes = Vec::with_capacity(size);
+				for &(offset, len) in runs {
+					bytes.extend_from_slice(slice(self.data, offset, len)?);
+				}
+				Ok(Cow::Owned(bytes))
+			}
+		}
+	}
+
+	pub fn open_stream(&self, path: &str) -> CfbResult<Cow<'a, [u8]>> {
+		let entry = self.cfb.open_stream(path)?;
+		self.stream(&entry)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::CompoundFileBuilder;
+	use crate::fat;
+
+	fn sample() -> Vec<u8> {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Small", (0..300).map(|i| i as u8).collect()).unwrap()
+			.create_stream("/Large", (0..5000).map(|i| (i / 3) as u8).collect()).unwrap();
+		let mut cursor = io::Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	// Swaps the second and third sector of a stream's chain along with their
+	// contents, so that the stream keeps its data but is no longer contiguous.
+	fn fragment(bytes: &mut [u8], path: &str) {
+		let cfb = CompoundFile::parse_from_reader(&mut io::Cursor::new(&*bytes)).unwrap();
+		let entry = cfb.open_stream(path).unwrap();
+		let (chunk_size, offsets) = cfb.stream_offsets(&entry).unwrap();
+		let sector_size = cfb.header.sector_size();
+		let (table, table_sectors) = if chunk_size == fat::MINIFAT_SECTOR_SIZE {
+			(&cfb.minifat, fat::get_chain(&cfb.fat, cfb.header.minifat_first_sector).unwrap())
+		} else {
+			(&cfb.fat, cfb.difat.clone())
+		};
+		let chain = fat::get_chain(table, entry.starting_sector).unwrap();
+		let mut set_next = |sector: u32, next: u32| {
+			let per_sector = sector_size / 4;
+			let offset = cfb.header.sector_offset(table_sectors[sector as usize / per_sector]) + sector as usize % per_sector * 4;
+			bytes[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+		};
+		set_next(chain[0], chain[2]);
+		set_next(chain[2], chain[1]);
+		set_next(chain[1], chain.get(3).copied().unwrap_or(fat::ENDOFCHAIN));
+		let (first, second) = (offsets[1] as usize, offsets[2] as usize);
+		let second_chunk = bytes[second..second + chunk_size].to_vec();
+		bytes.copy_within(first..first + chunk_size, second);
+		bytes[first..first + chunk_size].copy_from_slice(&second_chunk);
+	}
+
+	#[test]
+	fn contiguous_streams_are_borrowed() {
+		let bytes = sample();
+		let expected = CompoundFile::parse_from_reader(&mut io::Cursor::new(&bytes)).unwrap();
+		let borrowed = CompoundFile::parse_from_slice(&bytes).unwrap();
+		assert_eq!(borrowed.cfb, CompoundFile::parse_metadata_from_reader(&mut io::Cursor::new(&bytes)).unwrap());
+		for path in ["/Small", "/Large"] {
+			let stream = borrowed.open_stream(path).unwrap();
+			assert!(matches!(stream, Cow::Borrowed(slice) if bytes.as_ptr_range().contains(&slice.as_ptr())));
+			assert_eq!(*stream, *expected.open_stream(path).unwrap().data.borrow());
+		}
+	}
+
+	#[test]
+	fn fragmented_streams_are_copied() {
+		let mut bytes = sample();
+		let expected = CompoundFile::parse_from_reader(&mut io::Cursor::new(&bytes)).unwrap();
+		fragment(&mut bytes, "/Small");
+		fragment(&mut bytes, "/Large");
+		let borrowed = CompoundFile::parse_from_slice(&bytes).unwrap();
+		for path in ["/Small", "/Large"] {
+			let stream = borrowed.open_stream(path).unwrap();
+			assert!(matches!(stream, Cow::Owned(_)));
+			assert_eq!(*stream, *expected.open_stream(path).unwrap().data.borrow());
+		}
+	}
+
+	#[test]
+	fn truncated_slice_is_rejected() {
+		let bytes = sample();
+		assert!(matches!(CompoundFile::parse_from_slice(&bytes[..100]), Err(CfbError::Io(_))));
+		// every sector is either metadata or stream data
+		let truncated = &bytes[..bytes.len() - 512];
+		let result = CompoundFile::parse_from_slice(truncated)
+			.and_then(|borrowed| ["/Small", "/Large"].iter().try_for_each(|path| borrowed.open_stream(path).map(drop)));
+		assert!(result.is_err());
+	}
+}
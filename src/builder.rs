@@ -0,0 +1,328 @@
+use crate::cfb::{CompoundFile, CompoundFileHeader};
+use crate::dir::{self, DirectoryEntry};
+use crate::error::{CfbError, CfbResult};
+
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+	name: String,
+	object_type: u8,
+	clsid: [u8; 16],
+	state_bits: u32,
+	creation_time: Option<DateTime<Utc>>,
+	modified_time: Option<DateTime<Utc>>,
+	data: Vec<u8>,
+	children: Vec<usize>,
+}
+
+impl Node {
+	fn new(name: &str, object_type: u8) -> Self {
+		Self {
+			name: name.to_string(),
+			object_type,
+			clsid: [0; 16],
+			state_bits: 0,
+			creation_time: None,
+			modified_time: None,
+			data: Vec::new(),
+			children: Vec::new(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompoundFileBuilder {
+	header: CompoundFileHeader,
+	nodes: Vec<Node>,
+}
+
+pub(crate) fn split_path(path: &str) -> impl Iterator<Item = &str> {
+	path.split('/').filter(|component| !component.is_empty())
+}
+
+pub(crate) fn validate_name(name: &str) -> CfbResult<()> {
+	if name.encode_utf16().count() > dir::MAX_NAME_LEN {
+		return Err(CfbError::NameTooLong { name: name.to_string() });
+	}
+	if name.contains(['/', '\\', ':', '!']) {
+		return Err(CfbError::InvalidName { name: name.to_string() });
+	}
+	Ok(())
+}
+
+// Builds a balanced binary search tree from sorted sibling IDs and colors it
+// so that it is a valid red-black tree: every level that is completely filled
+// is black, and the nodes on the partially filled bottom level are red.
+pub(crate) fn build_sibling_tree(entries: &mut [DirectoryEntry], ids: &[u32], depth: u32, red_depth: u32) -> u32 {
+	if ids.is_empty() {
+		return dir::NOSTREAM;
+	}
+	let mid = ids.len() / 2;
+	let id = ids[mid];
+	let left_sibling_id = build_sibling_tree(entries, &ids[..mid], depth + 1, red_depth);
+	let right_sibling_id = build_sibling_tree(entries, &ids[mid + 1..], depth + 1, red_depth);
+	let entry = &mut entries[id as usize];
+	entry.left_sibling_id = left_sibling_id;
+	entry.right_sibling_id = right_sibling_id;
+	entry.color_flag = if depth >= red_depth { dir::COLOR_RED } else { dir::COLOR_BLACK };
+	id
+}
+
+impl CompoundFileBuilder {
+	pub fn new(header: CompoundFileHeader) -> Self {
+		Self {
+			header,
+			nodes: vec![Node::new("Root Entry", dir::OBJECT_ROOT_STORAGE)],
+		}
+	}
+
+	pub fn new_v3() -> Self {
+		Self::new(CompoundFileHeader::new_v3())
+	}
+
+	pub fn new_v4() -> Self {
+		Self::new(CompoundFileHeader::new_v4())
+	}
+
+	fn find_child(&self, parent: usize, name: &str) -> Option<usize> {
+		let name = dir::EntryName::from(name);
+		self.nodes[parent].children.iter().copied().find(|&child| dir::EntryName::from(self.nodes[child].name.as_str()) == name)
+	}
+
+	fn find(&self, path: &str) -> CfbResult<usize> {
+		let mut index = 0;
+		let mut entry_path = String::new();
+		for component in split_path(path) {
+			if self.nodes[index].object_type == dir::OBJECT_STREAM {
+				return Err(CfbError::NotAStorage { path: entry_path });
+			}
+			entry_path.push('/');
+			entry_path.push_str(component);
+			index = self.find_child(index, component).ok_or_else(|| CfbError::MissingEntry { path: entry_path.clone() })?;
+		}
+		Ok(index)
+	}
+
+	// Streams must have a zero CLSID and no timestamps ([MS-CFB] 2.6.1), so the
+	// setters for those only accept storages.
+	fn find_storage(&self, path: &str) -> CfbResult<usize> {
+		let index = self.find(path)?;
+		if self.nodes[index].object_type == dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStorage { path: path.to_string() });
+		}
+		Ok(index)
+	}
+
+	fn insert(&mut self, path: &str, node: Node) -> CfbResult<usize> {
+		let (parent_path, name) = path.trim_end_matches('/').rsplit_once('/').unwrap_or(("", path));
+		if name.is_empty() {
+			return Err(CfbError::InvalidName { name: name.to_string() });
+		}
+		validate_name(name)?;
+		let parent = self.find(parent_path)?;
+		if self.nodes[parent].object_type == dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStorage { path: parent_path.to_string() });
+		}
+		if self.find_child(parent, name).is_some() {
+			return Err(CfbError::DuplicateName { name: name.to_string() });
+		}
+		let index = self.nodes.len();
+		self.nodes.push(Node { name: name.to_string(), ..node });
+		self.nodes[parent].children.push(index);
+		Ok(index)
+	}
+
+	pub fn create_storage(&mut self, path: &str) -> CfbResult<&mut Self> {
+		self.insert(path, Node::new("", dir::OBJECT_STORAGE))?;
+		Ok(self)
+	}
+
+	pub fn create_stream(&mut self, path: &str, data: Vec<u8>) -> CfbResult<&mut Self> {
+		self.insert(path, Node { data, ..Node::new("", dir::OBJECT_STREAM) })?;
+		Ok(self)
+	}
+
+	pub fn set_clsid(&mut self, path: &str, clsid: [u8; 16]) -> CfbResult<&mut Self> {
+		let index = self.find_storage(path)?;
+		self.nodes[index].clsid = clsid;
+		Ok(self)
+	}
+
+	pub fn set_state_bits(&mut self, path: &str, state_bits: u32) -> CfbResult<&mut Self> {
+		let index = self.find(path)?;
+		self.nodes[index].state_bits = state_bits;
+		Ok(self)
+	}
+
+	pub fn set_creation_time(&mut self, path: &str, time: Option<DateTime<Utc>>) -> CfbResult<&mut Self> {
+		let index = self.find_storage(path)?;
+		if index == 0 && time.is_some() {
+			return Err(CfbError::RootCreationTime);
+		}
+		self.nodes[index].creation_time = time;
+		Ok(self)
+	}
+
+	pub fn set_modified_time(&mut self, path: &str, time: Option<DateTime<Utc>>) -> CfbResult<&mut Self> {
+		let index = self.find_storage(path)?;
+		self.nodes[index].modified_time = time;
+		Ok(self)
+	}
+
+	pub fn build(self) -> CfbResult<CompoundFile> {
+		// assign directory entry IDs in pre-order
+		let mut order = Vec::with_capacity(self.nodes.len());
+		let mut queue = vec![0usize];
+		while let Some(index) = queue.pop() {
+			order.push(index);
+			queue.extend(self.nodes[index].children.iter().rev());
+		}
+		let mut ids = vec![0u32; self.nodes.len()];
+		for (id, &index) in order.iter().enumerate() {
+			ids[index] = id as u32;
+		}
+
+		let mut entries = vec![DirectoryEntry::default(); self.nodes.len()];
+		let mut children = vec![Vec::new(); self.nodes.len()];
+		for (index, node) in self.nodes.into_iter().enumerate() {
+			let id = ids[index] as usize;
+			children[id] = node.children.iter().map(|&child| ids[child]).collect::<Vec<u32>>();
+			entries[id] = DirectoryEntry {
+				name: node.name,
+				object_type: node.object_type,
+				color_flag: dir::COLOR_BLACK,
+				clsid: node.clsid,
+				state_bits: node.state_bits,
+				creation_time: node.creation_time,
+				modified_time: node.modified_time,
+				data: node.data.into(),
+				..Default::default()
+			};
+		}
+
+		// link each storage's children into a red-black tree
+		for (id, mut child_ids) in children.into_iter().enumerate() {
+			child_ids.sort_by_cached_key(|&id| dir::EntryName::from(entries[id as usize].name.as_str()));
+			let red_depth = (child_ids.len() as u32 + 1).ilog2();
+			entries[id].child_id = build_sibling_tree(&mut entries, &child_ids, 0, red_depth);
+		}
+
+		CompoundFile::from_entries(self.header, entries)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::io::Cursor;
+
+	use chrono::TimeZone;
+
+	fn write(cfb: &CompoundFile) -> Vec<u8> {
+		let mut cursor = Cursor::new(Vec::new());
+		cfb.write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	fn write_and_parse(cfb: &CompoundFile) -> CompoundFile {
+		CompoundFile::parse_from_reader(&mut Cursor::new(write(cfb))).unwrap()
+	}
+
+	fn entry<'a>(cfb: &'a CompoundFile, name: &str) -> &'a DirectoryEntry {
+		cfb.dirs.iter().find(|entry| entry.name == name).unwrap()
+	}
+
+	fn stream_name(i: usize) -> String {
+		"s".repeat(i % 7 + 1) + &i.to_string()
+	}
+
+	#[test]
+	fn build_write_parse_round_trip() {
+		let time = Utc.with_ymd_and_hms(2021, 6, 1, 8, 0, 0).unwrap();
+		let mut builder = CompoundFileBuilder::new_v4();
+		builder.create_storage("/Storage").unwrap()
+			.set_clsid("/Storage", [9; 16]).unwrap()
+			.set_state_bits("/Storage", 3).unwrap()
+			.set_creation_time("/Storage", Some(time)).unwrap()
+			.set_modified_time("/Storage", Some(time)).unwrap();
+		// enough siblings of different name lengths for a partially filled bottom level
+		for i in 0..20 {
+			builder.create_stream(&format!("/Storage/{}", stream_name(i)), vec![i as u8; i * 500]).unwrap();
+		}
+		let cfb = write_and_parse(&builder.build().unwrap());
+
+		assert_eq!(cfb.validate_directory(), Vec::new());
+		assert_eq!(cfb.check(), Vec::new());
+		let storage = entry(&cfb, "Storage");
+		assert_eq!((storage.clsid, storage.state_bits, storage.creation_time, storage.modified_time), ([9; 16], 3, Some(time), Some(time)));
+		assert_eq!(storage.children.borrow().len(), 20);
+		for i in 0..20 {
+			assert_eq!(*entry(&cfb, &stream_name(i)).data.borrow(), vec![i as u8; i * 500]);
+		}
+	}
+
+	#[test]
+	fn rewriting_a_parsed_file_is_stable() {
+		for mut builder in [CompoundFileBuilder::new_v3(), CompoundFileBuilder::new_v4()] {
+			builder.create_storage("/Storage").unwrap();
+			builder.create_stream("/Storage/Small", vec![1; 3000]).unwrap();
+			builder.create_stream("/Large", vec![2; 70000]).unwrap();
+			let bytes = write(&builder.build().unwrap());
+			let cfb = CompoundFile::parse_from_reader(&mut Cursor::new(&bytes)).unwrap();
+			assert_eq!(write(&cfb), bytes);
+			assert_eq!(bytes.len() % cfb.header.sector_size(), 0);
+		}
+	}
+
+	#[test]
+	fn invalid_paths_are_rejected() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap();
+		builder.create_stream("/Stream", Vec::new()).unwrap();
+		assert!(matches!(builder.create_stream("/STORAGE", Vec::new()), Err(CfbError::DuplicateName { .. })));
+		assert!(matches!(builder.create_stream("/Stream/Child", Vec::new()), Err(CfbError::NotAStorage { .. })));
+		assert!(matches!(builder.create_stream("/Missing/Child", Vec::new()), Err(CfbError::MissingEntry { .. })));
+		assert!(matches!(builder.create_stream("/Storage/a:b", Vec::new()), Err(CfbError::InvalidName { .. })));
+		assert!(matches!(builder.create_stream(&"n".repeat(32), Vec::new()), Err(CfbError::NameTooLong { .. })));
+	}
+
+	#[test]
+	fn stream_metadata_is_rejected() {
+		let time = Some(Utc.with_ymd_and_hms(2021, 6, 1, 8, 0, 0).unwrap());
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Stream", Vec::new()).unwrap();
+		assert!(matches!(builder.set_clsid("/Stream", [1; 16]), Err(CfbError::NotAStorage { .. })));
+		assert!(matches!(builder.set_creation_time("/Stream", time), Err(CfbError::NotAStorage { .. })));
+		assert!(matches!(builder.set_modified_time("/Stream", time), Err(CfbError::NotAStorage { .. })));
+		assert!(matches!(builder.set_creation_time("", time), Err(CfbError::RootCreationTime)));
+		builder.set_state_bits("/Stream", 1).unwrap()
+			.set_creation_time("", None).unwrap();
+	}
+
+	#[test]
+	fn spec_conforming_file_round_trips() {
+		let time = Utc.with_ymd_and_hms(2021, 6, 1, 8, 0, 0).unwrap();
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap()
+			.create_stream("/Storage/Stream", vec![1; 100]).unwrap()
+			.set_clsid("", [2; 16]).unwrap()
+			.set_modified_time("", Some(time)).unwrap()
+			.set_clsid("/Storage", [3; 16]).unwrap()
+			.set_creation_time("/Storage", Some(time)).unwrap()
+			.set_modified_time("/Storage", Some(time)).unwrap()
+			.set_state_bits("/Storage/Stream", 4).unwrap();
+		let bytes = write(&builder.build().unwrap());
+		let cfb = CompoundFile::parse_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		assert_eq!(cfb.check(), Vec::new());
+		assert_eq!(write(&cfb), bytes);
+		let metadata = |name| {
+			let entry = entry(&cfb, name);
+			(entry.clsid, entry.state_bits, entry.creation_time, entry.modified_time)
+		};
+		assert_eq!(metadata("Root Entry"), ([2; 16], 0, None, Some(time)));
+		assert_eq!(metadata("Storage"), ([3; 16], 0, Some(time), Some(time)));
+		assert_eq!(metadata("Stream"), ([0; 16], 4, None, None));
+	}
+}
@@ -1,8 +1,19 @@
-use crate::error::{BoxError, BoxResult};
+use crate::error::{CfbError, CfbResult};
 use crate::fat::{self, Fat};
 use crate::dir;
+use crate::check;
+use crate::recover;
+use crate::forensic;
+use crate::compact;
+use crate::convert;
+use crate::extract;
+use crate::walk;
+use crate::stream::StreamReader;
+use crate::borrowed::BorrowedCompoundFile;
 
-use std::io::{Read, Seek, SeekFrom};
+use std::collections::HashSet;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::Path;
 use std::rc::Rc;
 
 use nom::{
@@ -16,10 +27,29 @@ use nom::{
 pub const HEADER_SIZE: usize = 8 + 16 + (2 * 5) + 6 + (4 * 9) + (4 * 109);
 pub const SIGNATURE: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
 
+// largest chunk copy_stream_to reads at once
+pub const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
 pub const V3: u16 = 0x0003;
 pub const V4: u16 = 0x0004;
 
+pub const BYTE_ORDER: u16 = 0xFFFE;
+pub const MINI_SECTOR_SHIFT: u16 = 0x0006;
+pub const MINI_STREAM_CUTOFF_SIZE: u32 = 0x00001000;
+
+// How strictly a parsed header is checked against MS-CFB 2.2. Lenient only
+// requires what is needed to read the file (the signature and a sector shift
+// of 9 or 12), which accepts files from producers known to fill in the other
+// fields wrongly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderValidation {
+	#[default]
+	Strict,
+	Lenient,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CompoundFileHeader {
 	pub signature: [u8; 8],
 	pub clsid: [u8; 16],
@@ -38,16 +68,50 @@ pub struct CompoundFileHeader {
 	pub minifat_sectors: u32,
 	pub difat_first_sector: u32, // sector number
 	pub difat_sectors: u32,
+	#[cfg_attr(feature = "serde", serde(with = "crate::manifest::difat"))]
 	pub difat: [u32; 109],
 }
 
 impl CompoundFileHeader {
 	pub fn sector_size(&self) -> usize {
-		2usize.pow(self.sector_shift as u32)
+		2usize.checked_pow(self.sector_shift as u32).unwrap_or(usize::MAX)
 	}
 
 	pub fn sector_offset(&self, sector: u32) -> usize {
-		((sector as usize) + 1) * self.sector_size()
+		((sector as usize) + 1).saturating_mul(self.sector_size())
+	}
+
+	pub fn validate(&self, validation: HeaderValidation) -> CfbResult<()> {
+		if self.sector_shift != 9 && self.sector_shift != 12 {
+			return Err(CfbError::UnsupportedSectorShift { sector_shift: self.sector_shift });
+		}
+		if validation == HeaderValidation::Lenient {
+			return Ok(());
+		}
+		let sector_shift = match self.version_major {
+			V3 => 9,
+			V4 => 12,
+			version_major => return Err(CfbError::UnsupportedVersion { version_major }),
+		};
+		if self.sector_shift != sector_shift {
+			return Err(CfbError::SectorShiftMismatch { version_major: self.version_major, sector_shift: self.sector_shift });
+		}
+		if self.byte_order != BYTE_ORDER {
+			return Err(CfbError::InvalidByteOrder { byte_order: self.byte_order });
+		}
+		if self.mini_sector_shift != MINI_SECTOR_SHIFT {
+			return Err(CfbError::InvalidMiniSectorShift { mini_sector_shift: self.mini_sector_shift });
+		}
+		if self.mini_stream_cutoff_size != MINI_STREAM_CUTOFF_SIZE {
+			return Err(CfbError::InvalidMiniStreamCutoffSize { size: self.mini_stream_cutoff_size });
+		}
+		if self.reserved != [0; 6] {
+			return Err(CfbError::NonZeroReserved);
+		}
+		if self.version_major == V3 && self.dir_sectors != 0 {
+			return Err(CfbError::DirSectorsInV3 { dir_sectors: self.dir_sectors });
+		}
+		Ok(())
 	}
 
 	pub fn new_v3() -> Self {
@@ -56,15 +120,15 @@ impl CompoundFileHeader {
 			clsid: [0; 16],
 			version_minor: 0x003E,
 			version_major: V3,
-			byte_order: 0xFFFE,
+			byte_order: BYTE_ORDER,
 			sector_shift: 0x0009,
-			mini_sector_shift: 0x0006,
+			mini_sector_shift: MINI_SECTOR_SHIFT,
 			reserved: [0; 6],
 			dir_sectors: 0,
 			fat_sectors: 0,
 			dir_first_sector: fat::ENDOFCHAIN,
 			tx_sig_num: 0,
-			mini_stream_cutoff_size: 0x00001000,
+			mini_stream_cutoff_size: MINI_STREAM_CUTOFF_SIZE,
 			minifat_first_sector: fat::ENDOFCHAIN,
 			minifat_sectors: 0,
 			difat_first_sector: fat::ENDOFCHAIN,
@@ -79,15 +143,15 @@ impl CompoundFileHeader {
 			clsid: [0; 16],
 			version_minor: 0x003E,
 			version_major: V4,
-			byte_order: 0xFFFE,
+			byte_order: BYTE_ORDER,
 			sector_shift: 0x000C,
-			mini_sector_shift: 0x0006,
+			mini_sector_shift: MINI_SECTOR_SHIFT,
 			reserved: [0; 6],
 			dir_sectors: 0,
 			fat_sectors: 0,
 			dir_first_sector: fat::ENDOFCHAIN,
 			tx_sig_num: 0,
-			mini_stream_cutoff_size: 0x00001000,
+			mini_stream_cutoff_size: MINI_STREAM_CUTOFF_SIZE,
 			minifat_first_sector: fat::ENDOFCHAIN,
 			minifat_sectors: 0,
 			difat_first_sector: fat::ENDOFCHAIN,
@@ -136,6 +200,30 @@ impl CompoundFileHeader {
 			difat,
 		}))
 	}
+
+	pub fn write_to<W: Write>(&self, writer: &mut W) -> CfbResult<()> {
+		writer.write_all(&self.signature)?;
+		writer.write_all(&self.clsid)?;
+		writer.write_all(&self.version_minor.to_le_bytes())?;
+		writer.write_all(&self.version_major.to_le_bytes())?;
+		writer.write_all(&self.byte_order.to_le_bytes())?;
+		writer.write_all(&self.sector_shift.to_le_bytes())?;
+		writer.write_all(&self.mini_sector_shift.to_le_bytes())?;
+		writer.write_all(&self.reserved)?;
+		writer.write_all(&self.dir_sectors.to_le_bytes())?;
+		writer.write_all(&self.fat_sectors.to_le_bytes())?;
+		writer.write_all(&self.dir_first_sector.to_le_bytes())?;
+		writer.write_all(&self.tx_sig_num.to_le_bytes())?;
+		writer.write_all(&self.mini_stream_cutoff_size.to_le_bytes())?;
+		writer.write_all(&self.minifat_first_sector.to_le_bytes())?;
+		writer.write_all(&self.minifat_sectors.to_le_bytes())?;
+		writer.write_all(&self.difat_first_sector.to_le_bytes())?;
+		writer.write_all(&self.difat_sectors.to_le_bytes())?;
+		for sector in self.difat {
+			writer.write_all(&sector.to_le_bytes())?;
+		}
+		Ok(())
+	}
 }
 
 impl Default for CompoundFileHeader {
@@ -151,192 +239,1144 @@ impl std::fmt::Display for CompoundFileHeader {
 }
 
 
-fn get_sector_bytes<R: Read + Seek>(reader: &mut R, buf: &mut Vec<u8>, header: &CompoundFileHeader, sector: u32) -> BoxResult<()> {
-	buf.clear();
-	buf.resize(header.sector_size(), 0);
-	reader.seek(SeekFrom::Start(header.sector_offset(sector) as u64))?;
-	reader.read_exact(buf)?;
+pub(crate) fn extend_fat(buf: &[u8], header: &CompoundFileHeader, sector: u32, entries: &mut Vec<u32>) -> CfbResult<()> {
+	// the sector size, not the version, decides the number of entries per sector
+	match header.sector_shift {
+		9 => {
+			let (_, fat) = fat::FatV3::parse(buf).map_err(|_| CfbError::InvalidFatSector { sector })?;
+			entries.extend_from_slice(fat.entries());
+		}
+		12 => {
+			let (_, fat) = fat::FatV4::parse(buf).map_err(|_| CfbError::InvalidFatSector { sector })?;
+			entries.extend_from_slice(fat.entries());
+		}
+		_ => {}
+	}
 	Ok(())
 }
 
-fn get_fat_data<R: Read + Seek>(reader: &mut R, buf: &mut Vec<u8>, header: &CompoundFileHeader, entries: &[u32], mut sector: u32, size: usize) -> BoxResult<Vec<u8>> {
-	let mut bytes = Vec::new();
-	while sector != fat::ENDOFCHAIN {
-		get_sector_bytes(reader, buf, header, sector)?;
-		bytes.extend_from_slice(&buf);
-		if let Some(new_sector) = entries.get(sector as usize) {
-			sector = *new_sector;
-		} else {
-			sector = fat::ENDOFCHAIN;
+pub(crate) fn parse_header(buf: &[u8], validation: HeaderValidation) -> CfbResult<CompoundFileHeader> {
+	let (_, header) = CompoundFileHeader::parse(buf).map_err(|_| CfbError::InvalidHeader)?;
+	header.validate(validation)?;
+	Ok(header)
+}
+
+// Bounds the sector counts in the header by the size of the file, so a crafted
+// header cannot make us allocate more than the file holds.
+pub(crate) fn check_sector_counts(header: &CompoundFileHeader, file_size: u64) -> CfbResult<()> {
+	let sector_size = header.sector_size() as u64;
+	let file_sectors = file_size.saturating_sub(sector_size).div_ceil(sector_size);
+	for sectors in [header.fat_sectors, header.difat_sectors] {
+		if sectors as u64 > file_sectors {
+			return Err(CfbError::SectorCountExceedsFile { sectors, file_sectors });
 		}
 	}
-	if size > bytes.len() || size < bytes.len() - header.sector_size() {
-		return Err("FAT stream size does not match number of sectors".into());
+	Ok(())
+}
+
+// Checks that sector may be read as the next sector of the DIFAT chain and
+// adds it to the visited ones.
+pub(crate) fn check_difat_sector(header: &CompoundFileHeader, visited: &mut HashSet<u32>, sector: u32) -> CfbResult<()> {
+	if visited.contains(&sector) {
+		return Err(CfbError::ChainCycle { start: header.difat_first_sector });
+	}
+	if visited.len() >= header.difat_sectors as usize {
+		return Err(CfbError::DifatSectorCount { expected: header.difat_sectors, actual: visited.len() + 1 });
 	}
-	bytes.resize(size, 0);
-	Ok(bytes)
+	if sector > fat::MAXREGSECT {
+		return Err(CfbError::SectorOutOfRange { sector });
+	}
+	visited.insert(sector);
+	Ok(())
 }
 
-fn get_minifat_data(entries: &[u32], mini_stream_bytes: &[u8], mut sector: u32, size: usize) -> BoxResult<Vec<u8>> {
-	let mut bytes = Vec::new();
-	while sector != fat::ENDOFCHAIN {
-		let start = (sector as usize) * fat::MINIFAT_SECTOR_SIZE;
-		bytes.extend_from_slice(&mini_stream_bytes[start..start+(fat::MINIFAT_SECTOR_SIZE)]);
-		if let Some(new_sector) = entries.get(sector as usize) {
-			sector = *new_sector;
-		} else {
-			sector = fat::ENDOFCHAIN;
+pub(crate) fn check_difat_sectors_marked(fat: &[u32], difat_sectors: &[u32]) -> CfbResult<()> {
+	for &sector in difat_sectors {
+		if fat.get(sector as usize) != Some(&fat::DIFSECT) {
+			return Err(CfbError::DifatSectorNotMarked { sector });
 		}
 	}
-	bytes.resize(size, 0);
-	Ok(bytes)
+	Ok(())
+}
+
+// Appends the FAT sector numbers listed in a DIFAT sector to difat and returns
+// the next DIFAT sector, which the last entry of a DIFAT sector points to.
+pub(crate) fn extend_difat(buf: &[u8], header: &CompoundFileHeader, sector: u32, difat: &mut Vec<u32>) -> CfbResult<u32> {
+	let mut entries = Vec::new();
+	extend_fat(buf, header, sector, &mut entries)?;
+	let next = entries.pop().unwrap_or(fat::ENDOFCHAIN);
+	difat.extend(entries.into_iter().take_while(|&sector| sector != fat::FREESECT));
+	Ok(next)
 }
 
-fn get_directory_data<R: Read + Seek>(reader: &mut R, buf: &mut Vec<u8>, header: &CompoundFileHeader, fat: &[u32], minifat: &[u32], mini_stream_bytes: &[u8], entry: &Rc<dir::DirectoryEntry>) -> BoxResult<Vec<u8>> {
-	if entry.object_type != dir::OBJECT_STREAM {
-		return Err("Directory entry is not a stream object".into());
+pub(crate) fn check_difat(header: &CompoundFileHeader, difat: &[u32], difat_sectors: &[u32]) -> CfbResult<()> {
+	if difat_sectors.len() != header.difat_sectors as usize {
+		return Err(CfbError::DifatSectorCount { expected: header.difat_sectors, actual: difat_sectors.len() });
 	}
-	if entry.stream_size < header.mini_stream_cutoff_size as u64 {
-		get_minifat_data(minifat, mini_stream_bytes, entry.starting_sector, entry.stream_size as usize)
-	} else {
-		get_fat_data(reader, buf, header, fat, entry.starting_sector, entry.stream_size as usize)
+	if difat.len() != header.fat_sectors as usize {
+		return Err(CfbError::FatSectorCount { expected: header.fat_sectors, actual: difat.len() });
 	}
+	Ok(())
 }
 
-fn extend_fat(buf: &[u8], header: &CompoundFileHeader, entries: &mut Vec<u32>) -> BoxResult<()> {
-	match header.version_major {
-		V3 => {
-			let (_, fat) = fat::FatV3::parse(&buf).map_err(|err| BoxError::from(err.to_owned()))?;
-			entries.extend_from_slice(fat.entries());
-		}
-		V4 => {
-			let (_, fat) = fat::FatV4::parse(&buf).map_err(|err| BoxError::from(err.to_owned()))?;
-			entries.extend_from_slice(fat.entries());
-		}
-		_ => {}
+pub(crate) fn extend_directory(buf: &[u8], header: &CompoundFileHeader, sector: u32, dirs: &mut Vec<Rc<dir::DirectoryEntry>>) -> CfbResult<()> {
+	let mut input = buf;
+	for _ in 0..header.sector_size() / dir::ENTRY_SIZE {
+		let (new_input, entry) = dir::DirectoryEntry::parse(input).map_err(|_| CfbError::InvalidDirectorySector { sector })?;
+		input = new_input;
+		dirs.push(Rc::new(entry));
 	}
 	Ok(())
 }
 
-fn set_entry_children(entries: &mut Vec<Rc<dir::DirectoryEntry>>, entry_index: usize) -> BoxResult<()> {
+// Links the children of every storage reachable from entry_index. When not
+// strict, links to invalid, shared or duplicate entries are skipped instead.
+pub(crate) fn set_entry_children(entries: &[Rc<dir::DirectoryEntry>], entry_index: usize, strict: bool) -> CfbResult<()> {
 	if entries.len() <= entry_index {
 		return Ok(())
 	}
-	if entries[entry_index].child_id == dir::NOSTREAM {
-		return Ok(())
-	}
-	let mut child_ids_queue = Vec::new();
-	child_ids_queue.push(entries[entry_index].child_id);
-	while let Some(child_id) = child_ids_queue.pop() {
-		if let Some(child_entry) = entries.get(child_id as usize) {
-			if entries[entry_index].children.borrow().contains_key(&child_entry.name) {
-				return Err(format!("Duplicate directory entry name {}", child_entry.name).into());
-			}
-			entries[entry_index].children.borrow_mut().insert(child_entry.name.clone(), entries[child_id as usize].clone());
-			if child_entry.left_sibling_id != dir::NOSTREAM {
-				child_ids_queue.push(child_entry.left_sibling_id);
+	// every entry may be linked only once, which also rules out cycles
+	let mut visited = vec![false; entries.len()];
+	visited[entry_index] = true;
+	let mut storage_queue = vec![entry_index];
+	while let Some(storage_index) = storage_queue.pop() {
+		let mut child_ids_queue = Vec::new();
+		if entries[storage_index].child_id != dir::NOSTREAM {
+			child_ids_queue.push(entries[storage_index].child_id);
+		}
+		while let Some(child_id) = child_ids_queue.pop() {
+			if let Some(child_entry) = entries.get(child_id as usize) {
+				if visited[child_id as usize] {
+					if !strict {
+						continue;
+					}
+					return Err(CfbError::DirectoryEntryShared { id: child_id });
+				}
+				visited[child_id as usize] = true;
+				let child_name = dir::EntryName::from(child_entry.name.as_str());
+				let duplicate = entries[storage_index].children.borrow().contains_key(&child_name);
+				if duplicate && strict {
+					return Err(CfbError::DuplicateName { name: child_entry.name.clone() });
+				}
+				if child_entry.left_sibling_id != dir::NOSTREAM {
+					child_ids_queue.push(child_entry.left_sibling_id);
+				}
+				if child_entry.right_sibling_id != dir::NOSTREAM {
+					child_ids_queue.push(child_entry.right_sibling_id);
+				}
+				if duplicate {
+					continue;
+				}
+				entries[storage_index].children.borrow_mut().insert(child_name, child_entry.clone());
+				if child_entry.child_id != dir::NOSTREAM {
+					storage_queue.push(child_id as usize);
+				}
+			} else if strict {
+				return Err(CfbError::InvalidDirectoryId { id: child_id });
 			}
-			if child_entry.right_sibling_id != dir::NOSTREAM {
-				child_ids_queue.push(child_entry.right_sibling_id);
+		}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetadataPhase {
+	Difat,
+	Fat,
+	MiniFat,
+	Directory,
+	Done,
+}
+
+// Parses the header, DIFAT, FAT, MiniFAT and directory of a compound file
+// without doing any I/O: next_sector returns the file offset of the sector to
+// read next, and push takes its bytes. The reader, slice and async parsers
+// only feed it sectors. Directory sectors are kept as bytes until
+// compound_file, so that the parser holds no Rc and can live across an await.
+#[derive(Debug, Clone)]
+pub(crate) struct MetadataParser {
+	header: CompoundFileHeader,
+	phase: MetadataPhase,
+	// sectors of the current FAT, MiniFAT or directory phase
+	chain: Vec<u32>,
+	index: usize,
+	next_difat_sector: u32,
+	visited_difat_sectors: HashSet<u32>,
+	difat: Vec<u32>,
+	difat_sectors: Vec<u32>,
+	fat: Vec<u32>,
+	minifat: Vec<u32>,
+	dir_sectors: Vec<(u32, Vec<u8>)>,
+}
+
+impl MetadataParser {
+	// header_bytes holds at least the HEADER_SIZE bytes of the header, and
+	// file_size bounds the sector counts it may specify.
+	pub(crate) fn new(header_bytes: &[u8], file_size: u64, validation: HeaderValidation) -> CfbResult<Self> {
+		let header = parse_header(header_bytes, validation)?;
+		check_sector_counts(&header, file_size)?;
+		Ok(Self {
+			header,
+			phase: MetadataPhase::Difat,
+			chain: Vec::new(),
+			index: 0,
+			next_difat_sector: header.difat_first_sector,
+			visited_difat_sectors: HashSet::new(),
+			difat: header.difat.iter().copied().take_while(|&sector| sector != fat::FREESECT).collect(),
+			difat_sectors: Vec::new(),
+			fat: Vec::new(),
+			minifat: Vec::new(),
+			dir_sectors: Vec::new(),
+		})
+	}
+
+	pub(crate) fn sector_size(&self) -> usize {
+		self.header.sector_size()
+	}
+
+	fn start_phase(&mut self, phase: MetadataPhase, chain: Vec<u32>) {
+		self.phase = phase;
+		self.chain = chain;
+		self.index = 0;
+	}
+
+	// Returns the file offset of the next sector to push, or None once the
+	// metadata is complete.
+	pub(crate) fn next_sector(&mut self) -> CfbResult<Option<u64>> {
+		loop {
+			let sector = match self.phase {
+				MetadataPhase::Difat if self.next_difat_sector == fat::ENDOFCHAIN => {
+					check_difat(&self.header, &self.difat, &self.difat_sectors)?;
+					self.start_phase(MetadataPhase::Fat, self.difat.clone());
+					continue;
+				}
+				MetadataPhase::Difat => {
+					check_difat_sector(&self.header, &mut self.visited_difat_sectors, self.next_difat_sector)?;
+					self.next_difat_sector
+				}
+				MetadataPhase::Done => return Ok(None),
+				_ if self.index < self.chain.len() => self.chain[self.index],
+				MetadataPhase::Fat => {
+					check_difat_sectors_marked(&self.fat, &self.difat_sectors)?;
+					self.start_phase(MetadataPhase::MiniFat, fat::get_chain(&self.fat, self.header.minifat_first_sector)?);
+					continue;
+				}
+				MetadataPhase::MiniFat => {
+					self.start_phase(MetadataPhase::Directory, fat::get_chain(&self.fat, self.header.dir_first_sector)?);
+					continue;
+				}
+				MetadataPhase::Directory => {
+					self.start_phase(MetadataPhase::Done, Vec::new());
+					continue;
+				}
+			};
+			if sector > fat::MAXREGSECT {
+				return Err(CfbError::SectorOutOfRange { sector });
 			}
-			if child_entry.child_id != dir::NOSTREAM {
-				set_entry_children(entries, child_id as usize)?;
+			return Ok(Some(self.header.sector_offset(sector) as u64));
+		}
+	}
+
+	// Takes the bytes of the sector last returned by next_sector.
+	pub(crate) fn push(&mut self, buf: &[u8]) -> CfbResult<()> {
+		match self.phase {
+			MetadataPhase::Difat => {
+				let sector = self.next_difat_sector;
+				self.difat_sectors.push(sector);
+				self.next_difat_sector = extend_difat(buf, &self.header, sector, &mut self.difat)?;
+				return Ok(());
 			}
-		} else {
-			return Err(format!("Invalid child ID {}", child_id).into());
+			MetadataPhase::Fat => extend_fat(buf, &self.header, self.chain[self.index], &mut self.fat)?,
+			MetadataPhase::MiniFat => extend_fat(buf, &self.header, self.chain[self.index], &mut self.minifat)?,
+			MetadataPhase::Directory => self.dir_sectors.push((self.chain[self.index], buf.to_vec())),
+			MetadataPhase::Done => return Ok(()),
 		}
+		self.index += 1;
+		Ok(())
 	}
+
+	// Parses the directory and links its entries once next_sector returned None.
+	pub(crate) fn compound_file(&self) -> CfbResult<CompoundFile> {
+		let mut dirs = Vec::new();
+		for (sector, buf) in &self.dir_sectors {
+			extend_directory(buf, &self.header, *sector, &mut dirs)?;
+		}
+		if dirs.is_empty() {
+			return Err(CfbError::MissingRootEntry);
+		}
+
+		// establish DirectoryEntry hierarchy
+		set_entry_children(&dirs, 0, true)?;
+
+		Ok(CompoundFile {
+			header: self.header,
+			difat: self.difat.clone(),
+			difat_sectors: self.difat_sectors.clone(),
+			fat: self.fat.clone(),
+			minifat: self.minifat.clone(),
+			dirs,
+		})
+	}
+}
+
+// File offsets of the sectors holding the mini stream, resolved once so that
+// looking up the mini sectors of many streams does not walk its chain again.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct MiniStream {
+	sector_offsets: Vec<u64>,
+	size: u64,
+}
+
+impl MiniStream {
+	pub(crate) fn new(cfb: &CompoundFile) -> CfbResult<Self> {
+		let root = cfb.dirs.first().ok_or(CfbError::MissingRootEntry)?;
+		if root.stream_size == 0 || root.starting_sector == fat::ENDOFCHAIN {
+			return Ok(Self::default());
+		}
+		let chain = fat::get_chain(&cfb.fat, root.starting_sector)?;
+		if (chain.len() as u64) * (cfb.header.sector_size() as u64) < root.stream_size {
+			return Err(CfbError::StreamSizeMismatch { id: 0, size: root.stream_size, sectors: chain.len() });
+		}
+		Ok(Self {
+			sector_offsets: chain.into_iter().map(|sector| cfb.header.sector_offset(sector) as u64).collect(),
+			size: root.stream_size,
+		})
+	}
+
+	fn offset(&self, header: &CompoundFileHeader, mini_sector: u32) -> CfbResult<u64> {
+		let sector_size = header.sector_size() as u64;
+		let start = mini_sector as u64 * fat::MINIFAT_SECTOR_SIZE as u64;
+		if start + fat::MINIFAT_SECTOR_SIZE as u64 > self.size {
+			return Err(CfbError::MiniSectorOutOfRange { sector: mini_sector });
+		}
+		let sector_offset = self.sector_offsets.get((start / sector_size) as usize)
+			.ok_or(CfbError::MiniSectorOutOfRange { sector: mini_sector })?;
+		Ok(sector_offset + start % sector_size)
+	}
+}
+
+// Merges the sectors or mini sectors of a stream at offsets into (offset,
+// length) reads covering its size bytes, joining sectors that follow each
+// other in the file.
+pub(crate) fn read_runs(chunk_size: usize, offsets: &[u64], size: usize) -> Vec<(u64, usize)> {
+	let mut runs: Vec<(u64, usize)> = Vec::new();
+	let mut remaining = size;
+	for &offset in offsets {
+		if remaining == 0 {
+			break;
+		}
+		let len = chunk_size.min(remaining);
+		remaining -= len;
+		match runs.last_mut() {
+			Some((start, run_len)) if *start + *run_len as u64 == offset => *run_len += len,
+			_ => runs.push((offset, len)),
+		}
+	}
+	runs
+}
+
+// Reads that load the data of the stream with directory entry id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StreamReads {
+	pub id: usize,
+	pub size: usize,
+	pub runs: Vec<(u64, usize)>,
+}
+
+fn allocate_chain(fat: &mut Vec<u32>, count: usize) -> u32 {
+	if count == 0 {
+		return fat::ENDOFCHAIN;
+	}
+	let start = fat.len() as u32;
+	for i in 1..count {
+		fat.push(start + i as u32);
+	}
+	fat.push(fat::ENDOFCHAIN);
+	start
+}
+
+fn write_padded<W: Write>(writer: &mut W, bytes: &[u8], align: usize) -> CfbResult<()> {
+	writer.write_all(bytes)?;
+	let padding = (align - bytes.len() % align) % align;
+	writer.write_all(&vec![0u8; padding])?;
 	Ok(())
 }
 
+fn write_entries_padded<W: Write>(writer: &mut W, entries: &[u32], sector_size: usize) -> CfbResult<()> {
+	let mut bytes = Vec::with_capacity(entries.len() * 4);
+	for entry in entries {
+		bytes.extend_from_slice(&entry.to_le_bytes());
+	}
+	while bytes.len() % sector_size != 0 {
+		bytes.extend_from_slice(&fat::FREESECT.to_le_bytes());
+	}
+	writer.write_all(&bytes)?;
+	Ok(())
+}
+
+// Sector allocation computed for writing a CompoundFile.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Layout {
+	pub header: CompoundFileHeader,
+	pub difat: Vec<u32>,
+	pub difat_sectors: Vec<u32>,
+	pub fat: Vec<u32>,
+	pub minifat: Vec<u32>,
+	pub mini_stream_size: usize,
+	pub regular_streams: Vec<usize>, // directory entry indices, in sector order
+	pub entries: Vec<(u32, u64)>, // (starting_sector, stream_size) per directory entry
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct CompoundFile {
 	pub header: CompoundFileHeader,
+	pub difat: Vec<u32>,
+	pub difat_sectors: Vec<u32>,
 	pub fat: Vec<u32>,
 	pub minifat: Vec<u32>,
 	pub dirs: Vec<Rc<dir::DirectoryEntry>>,
 }
 
 impl CompoundFile {
-	pub fn parse_from_reader<R: Read + Seek>(reader: &mut R) -> BoxResult<Self> {
-		let mut buf = Vec::with_capacity(512);
+	// Parses the header, FAT, MiniFAT and directory without loading any stream data.
+	// Use stream_reader to read streams on demand.
+	pub fn parse_metadata_from_reader<R: Read + Seek>(reader: &mut R) -> CfbResult<Self> {
+		Self::parse_metadata_from_reader_with(reader, HeaderValidation::Strict)
+	}
 
-		// read header
-		buf.resize(HEADER_SIZE, 0);
+	pub fn parse_metadata_from_reader_with<R: Read + Seek>(reader: &mut R, validation: HeaderValidation) -> CfbResult<Self> {
+		let mut buf = vec![0u8; HEADER_SIZE];
 		reader.read_exact(&mut buf)?;
-		let (_, header) = CompoundFileHeader::parse(&buf).map_err(|err| BoxError::from(err.to_owned()))?;
+		let mut parser = MetadataParser::new(&buf, reader.seek(SeekFrom::End(0))?, validation)?;
+		buf.resize(parser.sector_size(), 0);
+		while let Some(offset) = parser.next_sector()? {
+			reader.seek(SeekFrom::Start(offset))?;
+			reader.read_exact(&mut buf)?;
+			parser.push(&buf)?;
+		}
+		parser.compound_file()
+	}
+
+	// Parses the metadata of a compound file held in memory, borrowing stream
+	// data from data instead of copying it where possible.
+	pub fn parse_from_slice(data: &[u8]) -> CfbResult<BorrowedCompoundFile<'_>> {
+		BorrowedCompoundFile::parse(data)
+	}
 
-		// reserve buffer space for a single sector
-		buf.reserve(header.sector_size() - buf.len());
+	pub fn parse_from_reader<R: Read + Seek>(reader: &mut R) -> CfbResult<Self> {
+		Self::parse_from_reader_with(reader, HeaderValidation::Strict)
+	}
 
-		// get FAT
-		let mut fat = Vec::new();
-		for sector in header.difat {
-			if sector == fat::FREESECT {
-				break
+	pub fn parse_from_reader_with<R: Read + Seek>(reader: &mut R, validation: HeaderValidation) -> CfbResult<Self> {
+		let cfb = Self::parse_metadata_from_reader_with(reader, validation)?;
+		cfb.load_data(reader)?;
+		Ok(cfb)
+	}
+
+	// Reads the data of every stream from reader, which must hold the file
+	// this one was parsed from, e.g. after parse_metadata_from_reader.
+	pub fn load_data<R: Read + Seek>(&self, reader: &mut R) -> CfbResult<()> {
+		for reads in self.stream_reads()? {
+			let mut data = vec![0u8; reads.size];
+			let mut position = 0;
+			for (offset, len) in reads.runs {
+				reader.seek(SeekFrom::Start(offset))?;
+				reader.read_exact(&mut data[position..position + len])?;
+				position += len;
 			}
-			get_sector_bytes(reader, &mut buf, &header, sector)?;
-			extend_fat(&buf, &header, &mut fat)?;
+			*self.dirs[reads.id].data.borrow_mut() = data;
 		}
+		Ok(())
+	}
 
-		// get MiniFAT
-		let mut minifat = Vec::new();
-		let mut sector = header.minifat_first_sector;
-		while sector != fat::ENDOFCHAIN {
-			get_sector_bytes(reader, &mut buf, &header, sector)?;
-			extend_fat(&buf, &header, &mut minifat)?;
-			if let Some(new_sector) = fat.get(sector as usize) {
-				sector = *new_sector;
-			} else {
-				sector = fat::ENDOFCHAIN;
+	// Plans the reads of every stream's data, failing if a sector chain does
+	// not match the size of its stream.
+	pub(crate) fn stream_reads(&self) -> CfbResult<Vec<StreamReads>> {
+		let mini_stream = MiniStream::new(self)?;
+		let mut stream_reads = Vec::new();
+		for (id, entry) in self.dirs.iter().enumerate() {
+			if entry.object_type != dir::OBJECT_STREAM {
+				continue
+			}
+			let size = usize::try_from(entry.stream_size).map_err(|_| CfbError::StreamTooLarge { name: entry.name.clone() })?;
+			let (chunk_size, offsets) = self.stream_offsets_with(&mini_stream, entry)?;
+			if offsets.len() != size.div_ceil(chunk_size) {
+				return Err(CfbError::StreamSizeMismatch { id: id as u32, size: entry.stream_size, sectors: offsets.len() });
 			}
+			stream_reads.push(StreamReads { id, size, runs: read_runs(chunk_size, &offsets, size) });
 		}
+		Ok(stream_reads)
+	}
 
-		// get DirectoryEntry
-		let sector_directory_entry_count = header.sector_size() / dir::ENTRY_SIZE;
-		let mut dirs = Vec::new();
-		let mut sector = header.dir_first_sector;
-		while sector != fat::ENDOFCHAIN {
-			get_sector_bytes(reader, &mut buf, &header, sector)?;
-			let mut input: &[u8] = &buf;
-			for _ in 0..sector_directory_entry_count {
-				let (new_input, entry) = dir::DirectoryEntry::parse(input).map_err(|err| BoxError::from(err.to_owned()))?;
-				input = new_input;
-				dirs.push(Rc::new(entry));
+	// Fails unless every stream holds stream_size bytes of data, as streams
+	// are written from their data.
+	pub(crate) fn check_data_loaded(&self) -> CfbResult<()> {
+		for (id, entry) in self.dirs.iter().enumerate() {
+			if entry.object_type == dir::OBJECT_STREAM && entry.data.borrow().len() as u64 != entry.stream_size {
+				return Err(CfbError::DataNotLoaded { id: id as u32 });
 			}
-			if let Some(new_sector) = fat.get(sector as usize) {
-				sector = *new_sector;
-			} else {
-				sector = fat::ENDOFCHAIN;
+		}
+		Ok(())
+	}
+
+	// Parses as much of a damaged or truncated compound file as can be read,
+	// marking each stream as complete, truncated or unreadable instead of
+	// failing on the first bad chain.
+	pub fn recover_from_reader<R: Read + Seek>(reader: &mut R) -> CfbResult<recover::RecoveredFile> {
+		recover::recover(reader)
+	}
+
+	// Lists unallocated directory entries that still hold a name.
+	pub fn deleted_entries(&self) -> Vec<(u32, Rc<dir::DirectoryEntry>)> {
+		forensic::deleted_entries(self)
+	}
+
+	// Lists unallocated sectors and mini sectors that still hold data.
+	pub fn free_sectors_with_data<R: Read + Seek>(&self, reader: &mut R) -> CfbResult<Vec<forensic::FreeSector>> {
+		forensic::free_sectors_with_data(self, reader)
+	}
+
+	// Makes a best-effort attempt at reading the data of the deleted stream
+	// with directory entry ID id from its stale sector chain.
+	pub fn rebuild_deleted_stream<R: Read + Seek>(&self, reader: &mut R, id: u32) -> CfbResult<forensic::RebuiltStream> {
+		forensic::rebuild_deleted_stream(self, reader, id)
+	}
+
+	// Reads the bytes between the end of a stream and the end of its last
+	// sector or mini sector.
+	pub fn stream_slack<R: Read + Seek>(&self, reader: &mut R, entry: &dir::DirectoryEntry) -> CfbResult<forensic::Slack> {
+		Ok(self.stream_reader(reader, entry)?.read_slack()?)
+	}
+
+	// Reads the padding after the header in the first sector.
+	pub fn header_slack<R: Read + Seek>(&self, reader: &mut R) -> CfbResult<forensic::Slack> {
+		forensic::header_slack(self, reader)
+	}
+
+	// Reads the unused name bytes and unallocated entries of the directory sectors.
+	pub fn directory_slack<R: Read + Seek>(&self, reader: &mut R) -> CfbResult<Vec<forensic::Slack>> {
+		forensic::directory_slack(self, reader)
+	}
+
+	// Returns a copy with a packed directory and sectors assigned so that
+	// write_to writes every stream contiguously and drops free sectors.
+	pub fn compact(&self) -> CfbResult<CompoundFile> {
+		compact::compact(self)
+	}
+
+	// Returns a copy re-sectored for version_major, V3 or V4.
+	pub fn convert(&self, version_major: u16) -> CfbResult<CompoundFile> {
+		convert::convert(self, version_major)
+	}
+
+	// Writes every storage as a directory and every stream as a file below path.
+	pub fn extract(&self, path: &Path) -> CfbResult<()> {
+		extract::extract(self, path)
+	}
+
+	// Packs a directory tree written by extract back into a compound file.
+	pub fn pack(path: &Path, header: CompoundFileHeader) -> CfbResult<CompoundFile> {
+		extract::pack(path, header)
+	}
+
+	// Checks the allocation tables, sector chains and header fields for
+	// inconsistencies, collecting every problem found instead of stopping at the first.
+	pub fn check(&self) -> Vec<check::Problem> {
+		check::check(self)
+	}
+
+	// Checks the directory's red-black sibling trees and reachability of entries.
+	pub fn validate_directory(&self) -> Vec<dir::DirectoryProblem> {
+		dir::validate_tree(&self.dirs)
+	}
+
+	// Looks up a directory entry by its path from the root storage, such as
+	// "/__attach_version1.0_#00000000/__properties_version1.0".
+	pub fn entry(&self, path: &str) -> CfbResult<Rc<dir::DirectoryEntry>> {
+		let mut entry = self.dirs.first().cloned().ok_or(CfbError::MissingRootEntry)?;
+		let mut entry_path = String::new();
+		for component in path.split('/').filter(|component| !component.is_empty()) {
+			let child = entry.children.borrow().get(&dir::EntryName::from(component)).cloned();
+			entry_path.push('/');
+			entry_path.push_str(component);
+			entry = child.ok_or_else(|| CfbError::MissingEntry { path: entry_path.clone() })?;
+		}
+		Ok(entry)
+	}
+
+	pub fn open_storage(&self, path: &str) -> CfbResult<Rc<dir::DirectoryEntry>> {
+		let entry = self.entry(path)?;
+		if entry.object_type != dir::OBJECT_STORAGE && entry.object_type != dir::OBJECT_ROOT_STORAGE {
+			return Err(CfbError::NotAStorage { path: path.to_string() });
+		}
+		Ok(entry)
+	}
+
+	pub fn open_stream(&self, path: &str) -> CfbResult<Rc<dir::DirectoryEntry>> {
+		let entry = self.entry(path)?;
+		if entry.object_type != dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStream { path: path.to_string() });
+		}
+		Ok(entry)
+	}
+
+	// Iterates over every entry in the directory tree as (path, depth, entry).
+	pub fn walk(&self, order: walk::WalkOrder) -> walk::Walker<'_> {
+		walk::Walker::new(self, order)
+	}
+
+	pub fn visit<V: walk::Visitor + ?Sized>(&self, visitor: &mut V) {
+		walk::visit(self, visitor)
+	}
+
+	pub fn exists(&self, path: &str) -> bool {
+		self.entry(path).is_ok()
+	}
+
+	pub fn is_storage(&self, path: &str) -> bool {
+		self.open_storage(path).is_ok()
+	}
+
+	pub fn is_stream(&self, path: &str) -> bool {
+		self.open_stream(path).is_ok()
+	}
+
+	// Opens a stream for reading on demand, following its FAT or MiniFAT chain in
+	// the compound file read by reader.
+	pub fn stream_reader<R: Read + Seek>(&self, reader: R, entry: &dir::DirectoryEntry) -> CfbResult<StreamReader<R>> {
+		let (chunk_size, offsets) = self.stream_offsets(entry)?;
+		Ok(StreamReader::new(reader, chunk_size, offsets, entry.stream_size))
+	}
+
+	// Copies a stream to sink without holding more than COPY_BUFFER_SIZE bytes of
+	// it in memory, reading contiguous sectors together. progress is called
+	// after every chunk with the number of bytes copied so far and the stream
+	// size, or once with (0, 0) for an empty stream. Returns the number of
+	// bytes copied.
+	pub fn copy_stream_to<R: Read + Seek, W: Write, P: FnMut(u64, u64)>(&self, reader: &mut R, path: &str, sink: &mut W, mut progress: P) -> CfbResult<u64> {
+		let entry = self.open_stream(path)?;
+		let size = entry.stream_size;
+		if size == 0 {
+			progress(0, 0);
+			sink.flush()?;
+			return Ok(0);
+		}
+
+		// follow the chain as the stream is copied rather than collecting it first
+		let sector_size = self.header.sector_size();
+		let (chunk_size, mut offsets): (usize, Box<dyn Iterator<Item = CfbResult<u64>>>) = if size < self.header.mini_stream_cutoff_size as u64 {
+			// a mini stream has fewer than cutoff / 64 sectors
+			let (chunk_size, offsets) = self.stream_offsets(&entry)?;
+			(chunk_size, Box::new(offsets.into_iter().map(Ok)))
+		} else {
+			let sectors = fat::ChainIter::new(&self.fat, entry.starting_sector);
+			(sector_size, Box::new(sectors.map(|sector| Ok(self.header.sector_offset(sector?) as u64))))
+		};
+
+		let mut buf = vec![0u8; COPY_BUFFER_SIZE.max(chunk_size).min(size as usize)];
+		let mut copied = 0u64;
+		let mut sectors = 0;
+		let mut next = offsets.next().transpose()?;
+		while copied < size {
+			let Some(start) = next.take() else {
+				return Err(CfbError::StreamSizeMismatch { id: self.entry_id(&entry), size, sectors });
+			};
+			// extend the run while the next sector follows the previous one
+			let remaining = (size - copied) as usize;
+			let mut len = chunk_size;
+			sectors += 1;
+			while len < remaining {
+				next = offsets.next().transpose()?;
+				match next {
+					Some(offset) if offset == start + len as u64 && len + chunk_size <= buf.len() => {
+						next = None;
+						len += chunk_size;
+						sectors += 1;
+					}
+					_ => break,
+				}
 			}
+			let len = len.min(remaining);
+			reader.seek(SeekFrom::Start(start))?;
+			reader.read_exact(&mut buf[..len])?;
+			sink.write_all(&buf[..len])?;
+			copied += len as u64;
+			progress(copied, size);
 		}
+		sink.flush()?;
+		Ok(copied)
+	}
 
-		// establish DirectoryEntry hierarchy
-		set_entry_children(&mut dirs, 0)?;
+	fn entry_id(&self, entry: &dir::DirectoryEntry) -> u32 {
+		self.dirs.iter().position(|dir| std::ptr::eq(dir.as_ref(), entry)).map_or(dir::NOSTREAM, |id| id as u32)
+	}
+
+	// Full path of an entry for error messages, or its name if it is not
+	// linked into the directory tree.
+	fn entry_path(&self, entry: &dir::DirectoryEntry) -> String {
+		self.walk(walk::WalkOrder::PreOrder)
+			.find(|(_, _, dir)| std::ptr::eq(*dir, entry))
+			.map_or_else(|| entry.name.clone(), |(path, _, _)| path)
+	}
 
-		// get mini stream data
-		let mini_stream_sector = dirs[0].starting_sector;
-		let mini_stream_size = dirs[0].stream_size as usize;
-		let mini_stream_bytes = if mini_stream_sector == fat::ENDOFCHAIN {
+	// Returns the size of the sectors or mini sectors holding a stream, and the
+	// file offset of each of them in chain order.
+	pub(crate) fn stream_offsets(&self, entry: &dir::DirectoryEntry) -> CfbResult<(usize, Vec<u64>)> {
+		let mini_stream = if self.is_mini_stream(entry) { MiniStream::new(self)? } else { MiniStream::default() };
+		self.stream_offsets_with(&mini_stream, entry)
+	}
+
+	fn is_mini_stream(&self, entry: &dir::DirectoryEntry) -> bool {
+		entry.stream_size > 0 && entry.stream_size < self.header.mini_stream_cutoff_size as u64
+	}
+
+	// stream_offsets with the mini stream resolved once by the caller.
+	pub(crate) fn stream_offsets_with(&self, mini_stream: &MiniStream, entry: &dir::DirectoryEntry) -> CfbResult<(usize, Vec<u64>)> {
+		if entry.object_type != dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStream { path: self.entry_path(entry) });
+		}
+		let sector_size = self.header.sector_size();
+		// empty streams often start at sector 0 rather than ENDOFCHAIN
+		let offsets = if entry.stream_size == 0 {
 			Vec::new()
+		} else if self.is_mini_stream(entry) {
+			fat::get_chain(&self.minifat, entry.starting_sector)?.into_iter()
+				.map(|mini_sector| mini_stream.offset(&self.header, mini_sector))
+				.collect::<CfbResult<Vec<u64>>>()?
 		} else {
-			get_fat_data(reader, &mut buf, &header, &fat, mini_stream_sector, mini_stream_size)?
+			fat::get_chain(&self.fat, entry.starting_sector)?.into_iter()
+				.map(|sector| self.header.sector_offset(sector) as u64)
+				.collect()
 		};
+		let chunk_size = if entry.stream_size < self.header.mini_stream_cutoff_size as u64 { fat::MINIFAT_SECTOR_SIZE } else { sector_size };
+		if (offsets.len() as u64) * (chunk_size as u64) < entry.stream_size {
+			return Err(CfbError::StreamSizeMismatch { id: self.entry_id(entry), size: entry.stream_size, sectors: offsets.len() });
+		}
+		Ok((chunk_size, offsets))
+	}
 
-		// get DirectoryEntry data
-		for entry in &dirs {
+	// Creates a compound file from linked directory entries and their data,
+	// assigning sectors as write_to would write them.
+	pub(crate) fn from_entries(header: CompoundFileHeader, entries: Vec<dir::DirectoryEntry>) -> CfbResult<Self> {
+		let mut cfb = CompoundFile {
+			header,
+			difat: Vec::new(),
+			difat_sectors: Vec::new(),
+			fat: Vec::new(),
+			minifat: Vec::new(),
+			dirs: entries.into_iter().map(Rc::new).collect(),
+		};
+		let layout = cfb.layout()?;
+		for (entry, &(starting_sector, stream_size)) in cfb.dirs.iter_mut().zip(&layout.entries) {
+			if let Some(entry) = Rc::get_mut(entry) {
+				entry.starting_sector = starting_sector;
+				entry.stream_size = stream_size;
+			}
+		}
+		cfb.header = layout.header;
+		cfb.difat = layout.difat;
+		cfb.difat_sectors = layout.difat_sectors;
+		cfb.fat = layout.fat;
+		cfb.minifat = layout.minifat;
+
+		set_entry_children(&cfb.dirs, 0, true)?;
+		Ok(cfb)
+	}
+
+	pub(crate) fn layout(&self) -> CfbResult<Layout> {
+		let mut header = self.header;
+		let sector_size = header.sector_size();
+		let fat_entries_per_sector = sector_size / 4;
+		if self.dirs.is_empty() {
+			return Err(CfbError::MissingRootEntry);
+		}
+
+		// assign mini stream sectors, in directory order
+		let mut entries = vec![(0u32, 0u64); self.dirs.len()];
+		let mut minifat = Vec::new();
+		let mut regular_streams = Vec::new();
+		let mut regular_sector_count = 0;
+		for (i, entry) in self.dirs.iter().enumerate() {
 			if entry.object_type != dir::OBJECT_STREAM {
 				continue
 			}
-			let mut data = entry.data.borrow_mut();
-			*data = get_directory_data(reader, &mut buf, &header, &fat, &minifat, &mini_stream_bytes, &entry)?;
+			let size = entry.data.borrow().len();
+			if header.version_major == V3 && size > u32::MAX as usize {
+				return Err(CfbError::StreamTooLarge { name: entry.name.clone() });
+			}
+			if size == 0 {
+				entries[i] = (fat::ENDOFCHAIN, 0);
+			} else if size < header.mini_stream_cutoff_size as usize {
+				let start = allocate_chain(&mut minifat, size.div_ceil(fat::MINIFAT_SECTOR_SIZE));
+				entries[i] = (start, size as u64);
+			} else {
+				entries[i] = (fat::ENDOFCHAIN, size as u64);
+				regular_streams.push(i);
+				regular_sector_count += size.div_ceil(sector_size);
+			}
 		}
+		let mini_stream_size = minifat.len() * fat::MINIFAT_SECTOR_SIZE;
 
-		Ok(Self {
+		// find the number of FAT and DIFAT sectors needed to hold every sector
+		let dir_sector_count = (self.dirs.len() * dir::ENTRY_SIZE).div_ceil(sector_size);
+		let minifat_sector_count = (minifat.len() * 4).div_ceil(sector_size);
+		let mini_stream_sector_count = mini_stream_size.div_ceil(sector_size);
+		let data_sector_count = dir_sector_count + minifat_sector_count + mini_stream_sector_count + regular_sector_count;
+		let (mut fat_sector_count, mut difat_sector_count) = (0, 0);
+		loop {
+			let total = data_sector_count + fat_sector_count + difat_sector_count;
+			let new_fat_sector_count = total.div_ceil(fat_entries_per_sector);
+			let new_difat_sector_count = new_fat_sector_count.saturating_sub(header.difat.len()).div_ceil(fat_entries_per_sector - 1);
+			if new_fat_sector_count == fat_sector_count && new_difat_sector_count == difat_sector_count {
+				break
+			}
+			fat_sector_count = new_fat_sector_count;
+			difat_sector_count = new_difat_sector_count;
+		}
+		if data_sector_count + fat_sector_count + difat_sector_count > fat::MAXREGSECT as usize {
+			return Err(CfbError::TooManySectors);
+		}
+
+		// assign sectors
+		let mut fat = Vec::with_capacity(fat_sector_count * fat_entries_per_sector);
+		let difat: Vec<u32> = (0..fat_sector_count as u32).collect();
+		fat.resize(fat_sector_count, fat::FATSECT);
+		let difat_sectors: Vec<u32> = (fat.len() as u32..(fat.len() + difat_sector_count) as u32).collect();
+		fat.resize(fat.len() + difat_sector_count, fat::DIFSECT);
+		let dir_first_sector = allocate_chain(&mut fat, dir_sector_count);
+		let minifat_first_sector = allocate_chain(&mut fat, minifat_sector_count);
+		entries[0] = (allocate_chain(&mut fat, mini_stream_sector_count), mini_stream_size as u64);
+		for &i in &regular_streams {
+			entries[i].0 = allocate_chain(&mut fat, (entries[i].1 as usize).div_ceil(sector_size));
+		}
+		fat.resize(fat_sector_count * fat_entries_per_sector, fat::FREESECT);
+
+		header.dir_sectors = if header.version_major == V3 { 0 } else { dir_sector_count as u32 };
+		header.fat_sectors = fat_sector_count as u32;
+		header.dir_first_sector = dir_first_sector;
+		header.minifat_first_sector = minifat_first_sector;
+		header.minifat_sectors = minifat_sector_count as u32;
+		header.difat_first_sector = difat_sectors.first().copied().unwrap_or(fat::ENDOFCHAIN);
+		header.difat_sectors = difat_sector_count as u32;
+		header.difat = [fat::FREESECT; 109];
+		for (dst, src) in header.difat.iter_mut().zip(&difat) {
+			*dst = *src;
+		}
+
+		Ok(Layout {
 			header,
+			difat,
+			difat_sectors,
 			fat,
 			minifat,
-			dirs,
+			mini_stream_size,
+			regular_streams,
+			entries,
 		})
 	}
-}
\ No newline at end of file
+
+	pub fn write_to<W: Write + Seek>(&self, writer: &mut W) -> CfbResult<()> {
+		self.check_data_loaded()?;
+		let layout = self.layout()?;
+		let header = &layout.header;
+		let sector_size = header.sector_size();
+		let fat_entries_per_sector = sector_size / 4;
+
+		// write header, padded to a full sector
+		let mut buf = Vec::with_capacity(sector_size);
+		header.write_to(&mut buf)?;
+		writer.seek(SeekFrom::Start(0))?;
+		write_padded(writer, &buf, sector_size)?;
+
+		// write FAT
+		write_entries_padded(writer, &layout.fat, sector_size)?;
+
+		// write DIFAT, each sector ending with the next DIFAT sector number
+		let difat_overflow = &layout.difat[layout.difat.len().min(header.difat.len())..];
+		for (i, chunk) in difat_overflow.chunks(fat_entries_per_sector - 1).enumerate() {
+			let mut entries = chunk.to_vec();
+			entries.resize(fat_entries_per_sector - 1, fat::FREESECT);
+			entries.push(layout.difat_sectors.get(i + 1).copied().unwrap_or(fat::ENDOFCHAIN));
+			write_entries_padded(writer, &entries, sector_size)?;
+		}
+
+		// write DirectoryEntry
+		buf.clear();
+		for (entry, &(starting_sector, stream_size)) in self.dirs.iter().zip(&layout.entries) {
+			if entry.object_type == dir::OBJECT_UNKNOWN {
+				dir::DirectoryEntry::default().write_to(&mut buf)?;
+				continue
+			}
+			dir::DirectoryEntry {
+				name: entry.name.clone(),
+				object_type: entry.object_type,
+				color_flag: entry.color_flag,
+				left_sibling_id: entry.left_sibling_id,
+				right_sibling_id: entry.right_sibling_id,
+				child_id: entry.child_id,
+				clsid: entry.clsid,
+				state_bits: entry.state_bits,
+				creation_time: entry.creation_time,
+				modified_time: entry.modified_time,
+				starting_sector,
+				stream_size,
+				..Default::default()
+			}.write_to(&mut buf)?;
+		}
+		while buf.len() % sector_size != 0 {
+			dir::DirectoryEntry::default().write_to(&mut buf)?;
+		}
+		writer.write_all(&buf)?;
+
+		// write MiniFAT
+		write_entries_padded(writer, &layout.minifat, sector_size)?;
+
+		// write mini stream
+		buf.clear();
+		for (entry, &(_, stream_size)) in self.dirs.iter().zip(&layout.entries).skip(1) {
+			if entry.object_type == dir::OBJECT_STREAM && stream_size > 0 && stream_size < header.mini_stream_cutoff_size as u64 {
+				let data = entry.data.borrow();
+				buf.extend_from_slice(&data);
+				buf.resize(buf.len().div_ceil(fat::MINIFAT_SECTOR_SIZE) * fat::MINIFAT_SECTOR_SIZE, 0);
+			}
+		}
+		write_padded(writer, &buf, sector_size)?;
+
+		// write regular streams
+		for &i in &layout.regular_streams {
+			write_padded(writer, &self.dirs[i].data.borrow(), sector_size)?;
+		}
+
+		writer.flush()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::builder::CompoundFileBuilder;
+
+	use std::io::Cursor;
+
+	fn write(cfb: &CompoundFile) -> Vec<u8> {
+		let mut cursor = Cursor::new(Vec::new());
+		cfb.write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	// Offset of the directory entry id within bytes.
+	fn entry_offset(cfb: &CompoundFile, id: usize) -> usize {
+		let per_sector = cfb.header.sector_size() / dir::ENTRY_SIZE;
+		let chain = fat::get_chain(&cfb.fat, cfb.header.dir_first_sector).unwrap();
+		cfb.header.sector_offset(chain[id / per_sector]) + (id % per_sector) * dir::ENTRY_SIZE
+	}
+
+	#[test]
+	fn empty_stream_starting_at_sector_0_parses() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Empty", Vec::new()).unwrap();
+		builder.create_stream("/Data", vec![1; 100]).unwrap();
+		let cfb = builder.build().unwrap();
+		let mut bytes = write(&cfb);
+		let id = cfb.dirs.iter().position(|entry| entry.name == "Empty").unwrap();
+		let offset = entry_offset(&cfb, id) + 116;
+		bytes[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
+
+		let cfb = CompoundFile::parse_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		let entry = cfb.open_stream("/Empty").unwrap();
+		assert_eq!(entry.starting_sector, 0);
+		assert!(entry.data.borrow().is_empty());
+		assert_eq!(*cfb.open_stream("/Data").unwrap().data.borrow(), vec![1; 100]);
+		assert_eq!(cfb.check(), Vec::new());
+		assert!(cfb.stream_reader(Cursor::new(&bytes), &entry).unwrap().is_empty());
+		assert!(CompoundFile::parse_from_slice(&bytes).unwrap().open_stream("/Empty").unwrap().is_empty());
+	}
+
+	#[test]
+	fn difat_cycle_is_detected() {
+		// more FAT sectors than the 109 the header holds
+		let size = 110 * 128 * 512;
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Large", vec![0; size]).unwrap();
+		let cfb = builder.build().unwrap();
+		assert_eq!(cfb.difat_sectors.len(), 1);
+		let mut bytes = write(&cfb);
+		let parsed = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		assert_eq!(parsed.difat, cfb.difat);
+
+		let sector = cfb.difat_sectors[0];
+		let offset = cfb.header.sector_offset(sector) + cfb.header.sector_size() - 4;
+		bytes[offset..offset + 4].copy_from_slice(&sector.to_le_bytes());
+		let result = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes));
+		assert!(matches!(result, Err(CfbError::ChainCycle { .. })));
+	}
+
+	#[test]
+	fn metadata_only_parse_is_not_written_without_data() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Small", vec![1; 100]).unwrap();
+		builder.create_stream("/Large", vec![2; 5000]).unwrap();
+		let bytes = write(&builder.build().unwrap());
+
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		assert!(matches!(cfb.write_to(&mut Cursor::new(Vec::new())), Err(CfbError::DataNotLoaded { .. })));
+		assert!(matches!(cfb.compact(), Err(CfbError::DataNotLoaded { .. })));
+		assert!(matches!(cfb.convert(V4), Err(CfbError::DataNotLoaded { .. })));
+		let borrowed = CompoundFile::parse_from_slice(&bytes).unwrap();
+		assert!(matches!(borrowed.cfb.write_to(&mut Cursor::new(Vec::new())), Err(CfbError::DataNotLoaded { .. })));
+
+		cfb.load_data(&mut Cursor::new(&bytes)).unwrap();
+		let cfb = CompoundFile::parse_from_reader(&mut Cursor::new(write(&cfb))).unwrap();
+		assert_eq!(*cfb.open_stream("/Small").unwrap().data.borrow(), vec![1; 100]);
+		assert_eq!(*cfb.open_stream("/Large").unwrap().data.borrow(), vec![2; 5000]);
+	}
+
+	// Sets the FAT entry of sector in bytes, a file written from cfb.
+	fn set_fat_entry(bytes: &mut [u8], cfb: &CompoundFile, sector: u32, value: u32) {
+		let entries_per_sector = cfb.header.sector_size() / 4;
+		let offset = cfb.header.sector_offset(cfb.difat[sector as usize / entries_per_sector]) + sector as usize % entries_per_sector * 4;
+		bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+	}
+
+	#[test]
+	fn copy_stream_to_follows_chain() {
+		let large: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Large", large.clone()).unwrap();
+		builder.create_stream("/Small", vec![1; 100]).unwrap();
+		builder.create_stream("/Empty", Vec::new()).unwrap();
+		let bytes = write(&builder.build().unwrap());
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).unwrap();
+
+		let mut calls = Vec::new();
+		let mut sink = Vec::new();
+		let copied = cfb.copy_stream_to(&mut Cursor::new(&bytes), "/Large", &mut sink, |copied, size| calls.push((copied, size))).unwrap();
+		assert_eq!((copied, sink), (200_000, large));
+		assert!(calls.len() > 1 && calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+		assert_eq!(calls.last(), Some(&(200_000, 200_000)));
+
+		let mut sink = Vec::new();
+		cfb.copy_stream_to(&mut Cursor::new(&bytes), "/Small", &mut sink, |_, _| {}).unwrap();
+		assert_eq!(sink, vec![1; 100]);
+
+		let mut calls = Vec::new();
+		let copied = cfb.copy_stream_to(&mut Cursor::new(&bytes), "/Empty", &mut Vec::new(), |copied, size| calls.push((copied, size))).unwrap();
+		assert_eq!((copied, calls), (0, vec![(0, 0)]));
+	}
+
+	#[test]
+	fn copy_stream_to_rejects_broken_chains() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Large", vec![1; 5000]).unwrap();
+		let cfb = builder.build().unwrap();
+		let start = cfb.open_stream("/Large").unwrap().starting_sector;
+		let bytes = write(&cfb);
+
+		let mut cycle = bytes.clone();
+		set_fat_entry(&mut cycle, &cfb, start + 1, start);
+		let parsed = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&cycle)).unwrap();
+		let result = parsed.copy_stream_to(&mut Cursor::new(&cycle), "/Large", &mut Vec::new(), |_, _| {});
+		assert!(matches!(result, Err(CfbError::ChainCycle { .. })));
+
+		let mut short = bytes;
+		set_fat_entry(&mut short, &cfb, start + 1, fat::ENDOFCHAIN);
+		let parsed = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&short)).unwrap();
+		let result = parsed.copy_stream_to(&mut Cursor::new(&short), "/Large", &mut Vec::new(), |_, _| {});
+		assert!(matches!(result, Err(CfbError::StreamSizeMismatch { size: 5000, sectors: 2, .. })));
+	}
+
+	#[test]
+	fn not_a_stream_reports_full_path() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap()
+			.create_storage("/Storage/Inner").unwrap();
+		let cfb = builder.build().unwrap();
+		let inner = cfb.open_storage("/Storage/Inner").unwrap();
+		let result = cfb.stream_reader(Cursor::new(Vec::new()), &inner);
+		assert!(matches!(result, Err(CfbError::NotAStream { path }) if path == "/Storage/Inner"));
+	}
+
+	// A version 3 file with a header field at offset replaced by value.
+	fn patched_header(offset: usize, value: &[u8]) -> Vec<u8> {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Small", vec![1; 100]).unwrap()
+			.create_stream("/Large", vec![2; 70000]).unwrap();
+		let mut bytes = write(&builder.build().unwrap());
+		bytes[offset..offset + value.len()].copy_from_slice(value);
+		bytes
+	}
+
+	#[test]
+	fn strict_validation_rejects_invalid_headers() {
+		let parse = |bytes: Vec<u8>| CompoundFile::parse_from_reader_with(&mut Cursor::new(bytes), HeaderValidation::Strict);
+		assert!(matches!(parse(patched_header(26, &4u16.to_le_bytes())), Err(CfbError::SectorShiftMismatch { version_major: 4, sector_shift: 9 })));
+		assert!(matches!(parse(patched_header(28, &0xFFFFu16.to_le_bytes())), Err(CfbError::InvalidByteOrder { byte_order: 0xFFFF })));
+		assert!(matches!(parse(patched_header(32, &7u16.to_le_bytes())), Err(CfbError::InvalidMiniSectorShift { mini_sector_shift: 7 })));
+		assert!(matches!(parse(patched_header(34, &[0, 0, 1, 0, 0, 0])), Err(CfbError::NonZeroReserved)));
+		assert!(matches!(parse(patched_header(40, &1u32.to_le_bytes())), Err(CfbError::DirSectorsInV3 { dir_sectors: 1 })));
+		assert!(matches!(parse(patched_header(44, &1000u32.to_le_bytes())), Err(CfbError::SectorCountExceedsFile { sectors: 1000, .. })));
+	}
+
+	#[test]
+	fn lenient_validation_accepts_invalid_headers() {
+		let expected = CompoundFile::parse_from_reader(&mut Cursor::new(patched_header(0, &SIGNATURE))).unwrap();
+		let patches: [(usize, &[u8]); 5] = [(26, &[4, 0]), (28, &[0xFF, 0xFF]), (32, &[7, 0]), (34, &[0, 0, 1, 0, 0, 0]), (40, &[1, 0, 0, 0])];
+		for (offset, value) in patches {
+			let cfb = CompoundFile::parse_from_reader_with(&mut Cursor::new(patched_header(offset, value)), HeaderValidation::Lenient).unwrap();
+			assert_eq!(cfb.dirs, expected.dirs);
+		}
+		// sector counts are still bounded by the file size
+		let result = CompoundFile::parse_from_reader_with(&mut Cursor::new(patched_header(44, &1000u32.to_le_bytes())), HeaderValidation::Lenient);
+		assert!(matches!(result, Err(CfbError::SectorCountExceedsFile { .. })));
+	}
+
+	fn lookup_sample() -> CompoundFile {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap()
+			.create_storage("/Storage/Inner").unwrap()
+			.create_stream("/Storage/Inner/Stream", vec![1; 10]).unwrap()
+			.create_stream("/Top", vec![2; 20]).unwrap();
+		builder.build().unwrap()
+	}
+
+	#[test]
+	fn entries_are_looked_up_by_path() {
+		let cfb = lookup_sample();
+		assert!(Rc::ptr_eq(&cfb.entry("").unwrap(), &cfb.dirs[0]));
+		assert!(Rc::ptr_eq(&cfb.entry("/").unwrap(), &cfb.dirs[0]));
+		assert_eq!(cfb.entry("/Storage/Inner/Stream").unwrap().name, "Stream");
+		// leading, trailing and repeated separators are ignored
+		assert_eq!(cfb.entry("Storage//Inner/").unwrap().name, "Inner");
+		assert_eq!(*cfb.open_stream("/Top").unwrap().data.borrow(), vec![2; 20]);
+		assert_eq!(cfb.open_storage("/").unwrap().object_type, dir::OBJECT_ROOT_STORAGE);
+		assert_eq!(cfb.open_storage("/Storage").unwrap().object_type, dir::OBJECT_STORAGE);
+	}
+
+	#[test]
+	fn lookup_errors_name_the_path() {
+		let cfb = lookup_sample();
+		assert!(matches!(cfb.entry("/Storage/Missing/Stream"), Err(CfbError::MissingEntry { path }) if path == "/Storage/Missing"));
+		assert!(matches!(cfb.entry("/Top/Child"), Err(CfbError::MissingEntry { path }) if path == "/Top/Child"));
+		assert!(matches!(cfb.open_stream("/Storage/Inner"), Err(CfbError::NotAStream { path }) if path == "/Storage/Inner"));
+		assert!(matches!(cfb.open_storage("/Storage/Inner/Stream"), Err(CfbError::NotAStorage { path }) if path == "/Storage/Inner/Stream"));
+	}
+
+	#[test]
+	fn exists_and_object_type_checks() {
+		let cfb = lookup_sample();
+		assert!(cfb.exists("/") && cfb.exists("/Storage/Inner/Stream"));
+		assert!(!cfb.exists("/Storage/Stream"));
+		assert!(cfb.is_storage("/Storage/Inner") && !cfb.is_storage("/Top") && !cfb.is_storage("/Missing"));
+		assert!(cfb.is_stream("/Top") && !cfb.is_stream("/Storage") && !cfb.is_stream("/Missing"));
+	}
+
+	#[test]
+	fn lookup_is_case_insensitive() {
+		let cfb = lookup_sample();
+		let stream = cfb.open_stream("/STORAGE/inner/sTrEaM").unwrap();
+		assert_eq!(stream.name, "Stream");
+		assert!(cfb.is_storage("/storage"));
+	}
+}
@@ -0,0 +1,304 @@
+use crate::cfb::{CompoundFile, BYTE_ORDER, MINI_SECTOR_SHIFT, MINI_STREAM_CUTOFF_SIZE, V3, V4};
+use crate::dir::{self, DirectoryProblem};
+use crate::error::CfbResult;
+use crate::fat::{self, ChainEnd};
+use crate::recover;
+
+use std::fmt::{Display, Formatter, Result};
+use std::io::{Read, Seek};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Owner {
+	Fat,
+	Difat,
+	Directory,
+	MiniFat,
+	MiniStream,
+	Stream { id: u32 },
+}
+
+impl Display for Owner {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		match self {
+			Self::Fat => write!(f, "FAT"),
+			Self::Difat => write!(f, "DIFAT"),
+			Self::Directory => write!(f, "directory"),
+			Self::MiniFat => write!(f, "MiniFAT"),
+			Self::MiniStream => write!(f, "mini stream"),
+			Self::Stream { id } => write!(f, "stream {}", id),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Problem {
+	// sector is claimed by more than one chain
+	SharedSector { sector: u32, first: Owner, second: Owner },
+	// mini sector is claimed by more than one mini stream chain
+	SharedMiniSector { sector: u32, first: Owner, second: Owner },
+	// FAT sector listed in the DIFAT is not marked FATSECT in the FAT
+	FatSectorNotMarked { sector: u32, value: u32 },
+	// DIFAT sector is not marked DIFSECT in the FAT
+	DifatSectorNotMarked { sector: u32, value: u32 },
+	// sector is marked FATSECT or DIFSECT but is not listed as such
+	UnexpectedMark { sector: u32, value: u32 },
+	// chain links to a special value or past the end of its allocation table
+	InvalidSector { owner: Owner, sector: u32 },
+	// chain links back to a sector it already visited
+	ChainCycle { owner: Owner, sector: u32 },
+	// chain has a different number of sectors than its size requires
+	ChainLength { owner: Owner, expected: usize, actual: usize },
+	// MiniFAT entry is allocated but lies past the end of the mini stream
+	MiniSectorPastStream { sector: u32, value: u32 },
+	// header field disagrees with the structure of the file
+	HeaderMismatch { field: &'static str, header: u64, actual: u64 },
+	Directory(DirectoryProblem),
+}
+
+impl Display for Problem {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		match self {
+			Self::SharedSector { sector, first, second } => write!(f, "sector {} is claimed by both {} and {}", sector, first, second),
+			Self::SharedMiniSector { sector, first, second } => write!(f, "mini sector {} is claimed by both {} and {}", sector, first, second),
+			Self::FatSectorNotMarked { sector, value } => write!(f, "FAT sector {} is marked {:#010X} instead of FATSECT", sector, value),
+			Self::DifatSectorNotMarked { sector, value } => write!(f, "DIFAT sector {} is marked {:#010X} instead of DIFSECT", sector, value),
+			Self::UnexpectedMark { sector, value } => write!(f, "sector {} is marked {:#010X} but is not used as such", sector, value),
+			Self::InvalidSector { owner, sector } => write!(f, "{} chain links to invalid sector {:#010X}", owner, sector),
+			Self::ChainCycle { owner, sector } => write!(f, "{} chain links back to sector {}", owner, sector),
+			Self::ChainLength { owner, expected, actual } => write!(f, "{} chain has {} sectors, expected {}", owner, actual, expected),
+			Self::MiniSectorPastStream { sector, value } => write!(f, "mini sector {} is marked {:#010X} but lies past the end of the mini stream", sector, value),
+			Self::HeaderMismatch { field, header, actual } => write!(f, "header field {} is {}, expected {}", field, header, actual),
+			Self::Directory(problem) => Display::fmt(problem, f),
+		}
+	}
+}
+
+struct Checker<'a> {
+	cfb: &'a CompoundFile,
+	owners: Vec<Option<Owner>>,
+	mini_owners: Vec<Option<Owner>>,
+	problems: Vec<Problem>,
+}
+
+impl Checker<'_> {
+	fn claim(&mut self, sector: u32, owner: Owner) {
+		match self.owners.get_mut(sector as usize) {
+			Some(Some(first)) => {
+				let first = *first;
+				self.problems.push(Problem::SharedSector { sector, first, second: owner });
+			}
+			Some(slot) => *slot = Some(owner),
+			None => self.problems.push(Problem::InvalidSector { owner, sector }),
+		}
+	}
+
+	// Walks a chain in the FAT or MiniFAT, claiming its sectors for owner and
+	// returning the number of sectors visited.
+	fn walk(&mut self, start: u32, owner: Owner, mini: bool) -> usize {
+		let cfb = self.cfb;
+		let table = if mini { &cfb.minifat } else { &cfb.fat };
+		let (chain, end) = fat::get_partial_chain(table, start);
+		for &sector in &chain {
+			if !mini {
+				self.claim(sector, owner);
+			} else if let Some(Some(first)) = self.mini_owners.get(sector as usize) {
+				self.problems.push(Problem::SharedMiniSector { sector, first: *first, second: owner });
+			} else {
+				self.mini_owners[sector as usize] = Some(owner);
+			}
+		}
+		match end {
+			ChainEnd::EndOfChain => {}
+			ChainEnd::InvalidSector(sector) => self.problems.push(Problem::InvalidSector { owner, sector }),
+			ChainEnd::Cycle(sector) => self.problems.push(Problem::ChainCycle { owner, sector }),
+		}
+		chain.len()
+	}
+
+	fn expect_length(&mut self, owner: Owner, expected: usize, actual: usize) {
+		if expected != actual {
+			self.problems.push(Problem::ChainLength { owner, expected, actual });
+		}
+	}
+
+	fn expect_header(&mut self, field: &'static str, header: u64, actual: u64) {
+		if header != actual {
+			self.problems.push(Problem::HeaderMismatch { field, header, actual });
+		}
+	}
+}
+
+// Cross-checks the header, DIFAT, FAT, MiniFAT and directory of a parsed
+// compound file. Every sector may belong to at most one chain, special
+// markers in the FAT must match the sectors actually used as FAT and DIFAT
+// sectors, and every chain must be as long as the size it stores requires.
+pub fn check(cfb: &CompoundFile) -> Vec<Problem> {
+	let header = &cfb.header;
+	let sector_size = header.sector_size();
+	let mut checker = Checker {
+		cfb,
+		owners: vec![None; cfb.fat.len()],
+		mini_owners: vec![None; cfb.minifat.len()],
+		problems: cfb.validate_directory().into_iter().map(Problem::Directory).collect(),
+	};
+
+	// header fields
+	match header.version_major {
+		V3 => checker.expect_header("sector_shift", header.sector_shift as u64, 9),
+		V4 => checker.expect_header("sector_shift", header.sector_shift as u64, 12),
+		_ => checker.expect_header("version_major", header.version_major as u64, if header.sector_shift == 9 { V3 } else { V4 } as u64),
+	}
+	checker.expect_header("byte_order", header.byte_order as u64, BYTE_ORDER as u64);
+	checker.expect_header("mini_sector_shift", header.mini_sector_shift as u64, MINI_SECTOR_SHIFT as u64);
+	checker.expect_header("mini_stream_cutoff_size", header.mini_stream_cutoff_size as u64, MINI_STREAM_CUTOFF_SIZE as u64);
+	checker.expect_header("reserved", header.reserved.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64), 0);
+	checker.expect_header("fat_sectors", header.fat_sectors as u64, cfb.difat.len() as u64);
+	checker.expect_header("difat_sectors", header.difat_sectors as u64, cfb.difat_sectors.len() as u64);
+	checker.expect_header("difat_first_sector", header.difat_first_sector as u64, cfb.difat_sectors.first().copied().unwrap_or(fat::ENDOFCHAIN) as u64);
+
+	// FAT and DIFAT sectors and their markers
+	for &sector in &cfb.difat {
+		checker.claim(sector, Owner::Fat);
+		if let Some(&value) = cfb.fat.get(sector as usize).filter(|&&value| value != fat::FATSECT) {
+			checker.problems.push(Problem::FatSectorNotMarked { sector, value });
+		}
+	}
+	for &sector in &cfb.difat_sectors {
+		checker.claim(sector, Owner::Difat);
+		if let Some(&value) = cfb.fat.get(sector as usize).filter(|&&value| value != fat::DIFSECT) {
+			checker.problems.push(Problem::DifatSectorNotMarked { sector, value });
+		}
+	}
+	for (sector, &value) in cfb.fat.iter().enumerate() {
+		let owner = checker.owners[sector];
+		if (value == fat::FATSECT && owner != Some(Owner::Fat)) || (value == fat::DIFSECT && owner != Some(Owner::Difat)) {
+			checker.problems.push(Problem::UnexpectedMark { sector: sector as u32, value });
+		}
+	}
+
+	// directory and MiniFAT chains
+	let dir_sectors = checker.walk(header.dir_first_sector, Owner::Directory, false);
+	if header.version_major == V3 {
+		checker.expect_header("dir_sectors", header.dir_sectors as u64, 0);
+	} else {
+		checker.expect_header("dir_sectors", header.dir_sectors as u64, dir_sectors as u64);
+	}
+	let minifat_sectors = checker.walk(header.minifat_first_sector, Owner::MiniFat, false);
+	checker.expect_header("minifat_sectors", header.minifat_sectors as u64, minifat_sectors as u64);
+
+	// mini stream, held in the root entry
+	let mini_stream_size = cfb.dirs.first().map_or(0, |root| root.stream_size);
+	if let Some(root) = cfb.dirs.first() {
+		let actual = checker.walk(root.starting_sector, Owner::MiniStream, false);
+		checker.expect_length(Owner::MiniStream, (root.stream_size as usize).div_ceil(sector_size), actual);
+	}
+	let mini_sectors = (mini_stream_size as usize).div_ceil(fat::MINIFAT_SECTOR_SIZE);
+	for (sector, &value) in cfb.minifat.iter().enumerate().skip(mini_sectors) {
+		if value != fat::FREESECT {
+			checker.problems.push(Problem::MiniSectorPastStream { sector: sector as u32, value });
+		}
+	}
+
+	// stream chains
+	for (id, entry) in cfb.dirs.iter().enumerate() {
+		// empty streams own no sectors, whatever their starting sector
+		if entry.object_type != dir::OBJECT_STREAM || entry.stream_size == 0 {
+			continue;
+		}
+		let owner = Owner::Stream { id: id as u32 };
+		let (mini, chunk_size) = if entry.stream_size < header.mini_stream_cutoff_size as u64 {
+			(true, fat::MINIFAT_SECTOR_SIZE)
+		} else {
+			(false, sector_size)
+		};
+		let actual = checker.walk(entry.starting_sector, owner, mini);
+		checker.expect_length(owner, (entry.stream_size as usize).div_ceil(chunk_size), actual);
+	}
+
+	checker.problems
+}
+
+// Checks a compound file straight from reader. Loading it as tolerantly as
+// recovery does lets the header counts and fields the parser rejects be
+// reported as problems too. Only a damaged header or a missing root entry
+// fail the check.
+pub fn check_reader<R: Read + Seek>(reader: &mut R) -> CfbResult<Vec<Problem>> {
+	Ok(check(&recover::recover_metadata(reader)?))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::builder::CompoundFileBuilder;
+	use crate::error::CfbError;
+
+	use std::io::Cursor;
+
+	fn write(cfb: &CompoundFile) -> Vec<u8> {
+		let mut cursor = Cursor::new(Vec::new());
+		cfb.write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	fn set_u32(bytes: &mut [u8], offset: usize, value: u32) {
+		bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+	}
+
+	#[test]
+	fn built_file_has_no_problems() {
+		let mut builder = CompoundFileBuilder::new_v4();
+		builder.create_storage("/Storage").unwrap();
+		builder.create_stream("/Storage/Small", vec![1; 100]).unwrap();
+		builder.create_stream("/Large", vec![2; 10000]).unwrap();
+		let cfb = builder.build().unwrap();
+		assert_eq!(check(&cfb), Vec::new());
+		assert_eq!(check_reader(&mut Cursor::new(write(&cfb))).unwrap(), Vec::new());
+	}
+
+	#[test]
+	fn header_counts_are_reported_from_raw_file() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Stream", vec![1; 100]).unwrap();
+		let mut bytes = write(&builder.build().unwrap());
+		// fat_sectors, difat_sectors and mini_stream_cutoff_size
+		set_u32(&mut bytes, 44, 2);
+		set_u32(&mut bytes, 72, 1);
+		set_u32(&mut bytes, 56, 2048);
+		assert!(CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).is_err());
+
+		let problems = check_reader(&mut Cursor::new(&bytes)).unwrap();
+		assert_eq!(problems, vec![
+			Problem::HeaderMismatch { field: "mini_stream_cutoff_size", header: 2048, actual: 4096 },
+			Problem::HeaderMismatch { field: "fat_sectors", header: 2, actual: 1 },
+			Problem::HeaderMismatch { field: "difat_sectors", header: 1, actual: 0 },
+		]);
+	}
+
+	#[test]
+	fn unmarked_difat_sector_fails_parse_and_is_reported() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Large", vec![0; 110 * 128 * 512]).unwrap();
+		let cfb = builder.build().unwrap();
+		let mut bytes = write(&cfb);
+		let sector = cfb.difat_sectors[0];
+		let entries_per_sector = cfb.header.sector_size() / 4;
+		let fat_sector = cfb.difat[sector as usize / entries_per_sector];
+		set_u32(&mut bytes, cfb.header.sector_offset(fat_sector) + sector as usize % entries_per_sector * 4, fat::FATSECT);
+
+		let result = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes));
+		assert!(matches!(result, Err(CfbError::DifatSectorNotMarked { sector: s }) if s == sector));
+		let problems = check_reader(&mut Cursor::new(&bytes)).unwrap();
+		assert!(problems.contains(&Problem::DifatSectorNotMarked { sector, value: fat::FATSECT }));
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn problems_serialize() {
+		let problem = Problem::ChainLength { owner: Owner::Stream { id: 3 }, expected: 2, actual: 1 };
+		let json = serde_json::to_string(&problem).unwrap();
+		assert_eq!(json, r#"{"ChainLength":{"owner":{"Stream":{"id":3}},"expected":2,"actual":1}}"#);
+	}
+}
@@ -0,0 +1,141 @@
+use crate::builder;
+use crate::cfb::CompoundFile;
+use crate::dir::{self, DirectoryEntry};
+use crate::error::{CfbError, CfbResult};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Returns the IDs linked into the sibling tree rooted at id, or None if the
+// tree links to an invalid entry or to the same entry twice.
+fn sibling_ids(entries: &[Rc<DirectoryEntry>], id: u32) -> Option<Vec<u32>> {
+	let mut visited = vec![false; entries.len()];
+	let mut ids = Vec::new();
+	let mut queue = vec![id];
+	while let Some(id) = queue.pop() {
+		if id == dir::NOSTREAM {
+			continue;
+		}
+		let entry = entries.get(id as usize)?;
+		if std::mem::replace(&mut visited[id as usize], true) {
+			return None;
+		}
+		ids.push(id);
+		queue.push(entry.left_sibling_id);
+		queue.push(entry.right_sibling_id);
+	}
+	ids.sort_unstable();
+	Some(ids)
+}
+
+// Returns a copy of cfb that keeps only the entries reachable from the root,
+// with their IDs packed in their original order and sectors assigned as
+// write_to writes them: every stream contiguous and no free sectors. Names,
+// CLSIDs, state bits, timestamps, stream data and sibling trees are kept as
+// they are, except for trees that link entries other than their storage's
+// children, which are rebuilt.
+pub fn compact(cfb: &CompoundFile) -> CfbResult<CompoundFile> {
+	if cfb.dirs.is_empty() {
+		return Err(CfbError::MissingRootEntry);
+	}
+	cfb.check_data_loaded()?;
+	let ids: HashMap<*const DirectoryEntry, u32> = cfb.dirs.iter()
+		.enumerate()
+		.map(|(id, entry)| (Rc::as_ptr(entry), id as u32))
+		.collect();
+	let children: Vec<Vec<u32>> = cfb.dirs.iter()
+		.map(|entry| entry.children.borrow().values().filter_map(|child| ids.get(&Rc::as_ptr(child)).copied()).collect())
+		.collect();
+
+	// find the entries reachable from the root and pack their IDs
+	let mut reachable = vec![false; cfb.dirs.len()];
+	reachable[0] = true;
+	let mut queue = vec![0usize];
+	while let Some(id) = queue.pop() {
+		for &child in &children[id] {
+			if !std::mem::replace(&mut reachable[child as usize], true) {
+				queue.push(child as usize);
+			}
+		}
+	}
+	let order: Vec<usize> = (0..cfb.dirs.len()).filter(|&id| reachable[id]).collect();
+	let mut new_ids = vec![dir::NOSTREAM; cfb.dirs.len()];
+	for (new_id, &id) in order.iter().enumerate() {
+		new_ids[id] = new_id as u32;
+	}
+	let remap = |id: u32| new_ids.get(id as usize).copied().unwrap_or(dir::NOSTREAM);
+
+	let mut entries: Vec<DirectoryEntry> = order.iter().map(|&id| {
+		let entry = &cfb.dirs[id];
+		DirectoryEntry {
+			name: entry.name.clone(),
+			object_type: entry.object_type,
+			color_flag: entry.color_flag,
+			left_sibling_id: remap(entry.left_sibling_id),
+			right_sibling_id: remap(entry.right_sibling_id),
+			child_id: remap(entry.child_id),
+			clsid: entry.clsid,
+			state_bits: entry.state_bits,
+			creation_time: entry.creation_time,
+			modified_time: entry.modified_time,
+			stream_size: entry.stream_size,
+			data: entry.data.clone(),
+			..Default::default()
+		}
+	}).collect();
+
+	// relink the children of storages whose sibling tree does not link exactly their children
+	for &id in &order {
+		let mut child_ids = children[id].clone();
+		child_ids.sort_unstable();
+		if sibling_ids(&cfb.dirs, cfb.dirs[id].child_id).is_some_and(|linked| linked == child_ids) {
+			continue;
+		}
+		// children are in name order, as build_sibling_tree expects
+		let child_ids: Vec<u32> = children[id].iter().map(|&child| new_ids[child as usize]).collect();
+		let red_depth = (child_ids.len() as u32 + 1).ilog2();
+		entries[new_ids[id] as usize].child_id = builder::build_sibling_tree(&mut entries, &child_ids, 0, red_depth);
+	}
+
+	CompoundFile::from_entries(cfb.header, entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::builder::CompoundFileBuilder;
+	use crate::edit::CompoundFileEditor;
+
+	use std::io::Cursor;
+
+	#[test]
+	fn compact_drops_removed_entries_and_sectors() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap();
+		builder.create_stream("/Storage/Large", vec![1; 50000]).unwrap();
+		builder.create_stream("/Small", vec![2; 200]).unwrap();
+		builder.create_stream("/Kept", vec![3; 9000]).unwrap();
+		let mut file = Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut file).unwrap();
+
+		let mut editor = CompoundFileEditor::open(file).unwrap();
+		editor.remove("/Storage").unwrap().replace_stream("/Small", vec![4; 100]).unwrap();
+		editor.commit().unwrap();
+		let mut file = editor.into_inner();
+		let edited_size = file.get_ref().len();
+		file.set_position(0);
+		let cfb = CompoundFile::parse_from_reader(&mut file).unwrap();
+
+		let mut compacted = Cursor::new(Vec::new());
+		cfb.compact().unwrap().write_to(&mut compacted).unwrap();
+		assert!(compacted.get_ref().len() < edited_size);
+		compacted.set_position(0);
+		let cfb = CompoundFile::parse_from_reader(&mut compacted).unwrap();
+		assert_eq!(cfb.check(), Vec::new());
+		assert_eq!(cfb.dirs.iter().filter(|entry| entry.object_type != dir::OBJECT_UNKNOWN).count(), 3);
+		assert!(!cfb.exists("/Storage"));
+		assert_eq!(*cfb.open_stream("/Small").unwrap().data.borrow(), vec![4; 100]);
+		assert_eq!(*cfb.open_stream("/Kept").unwrap().data.borrow(), vec![3; 9000]);
+	}
+}
@@ -0,0 +1,66 @@
+use crate::cfb::{CompoundFile, V3, V4};
+use crate::dir::{self, DirectoryEntry};
+use crate::error::{CfbError, CfbResult};
+
+use std::cell::RefCell;
+
+// Returns a copy of cfb re-sectored for another major version: 512-byte
+// sectors for version 3 and 4096-byte sectors for version 4. Directory entry
+// IDs, sibling trees, CLSIDs, state bits, timestamps and stream data are kept,
+// while the FAT, MiniFAT, DIFAT and header sector counts are recomputed.
+pub fn convert(cfb: &CompoundFile, version_major: u16) -> CfbResult<CompoundFile> {
+	let mut header = cfb.header;
+	header.sector_shift = match version_major {
+		V3 => 9,
+		V4 => 12,
+		_ => return Err(CfbError::UnsupportedVersion { version_major }),
+	};
+	header.version_major = version_major;
+	cfb.check_data_loaded()?;
+	let mut entries: Vec<DirectoryEntry> = cfb.dirs.iter()
+		.map(|entry| DirectoryEntry { children: RefCell::default(), ..DirectoryEntry::clone(entry) })
+		.collect();
+	// drop the unallocated entries padding the last directory sector, which
+	// would otherwise grow the directory on every round trip
+	while entries.len() > 1 && entries.last().is_some_and(|entry| entry.object_type == dir::OBJECT_UNKNOWN) {
+		entries.pop();
+	}
+	CompoundFile::from_entries(header, entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::builder::CompoundFileBuilder;
+
+	use std::io::Cursor;
+
+	fn write_and_parse(cfb: &CompoundFile) -> CompoundFile {
+		let mut cursor = Cursor::new(Vec::new());
+		cfb.write_to(&mut cursor).unwrap();
+		cursor.set_position(0);
+		CompoundFile::parse_from_reader(&mut cursor).unwrap()
+	}
+
+	#[test]
+	fn convert_between_versions() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap();
+		builder.create_stream("/Storage/Small", vec![1; 100]).unwrap();
+		builder.create_stream("/Large", vec![2; 20000]).unwrap();
+		builder.set_clsid("/Storage", [5; 16]).unwrap();
+		let v3 = write_and_parse(&builder.build().unwrap());
+
+		let v4 = write_and_parse(&v3.convert(V4).unwrap());
+		assert_eq!((v4.header.version_major, v4.header.sector_size()), (V4, 4096));
+		assert_eq!(v4.check(), Vec::new());
+		assert_eq!(v4.open_storage("/Storage").unwrap().clsid, [5; 16]);
+		assert_eq!(*v4.open_stream("/Large").unwrap().data.borrow(), vec![2; 20000]);
+
+		let back = write_and_parse(&v4.convert(V3).unwrap());
+		assert_eq!(back.header.sector_size(), 512);
+		assert_eq!(back.dirs, v3.dirs);
+		assert!(matches!(v3.convert(5), Err(CfbError::UnsupportedVersion { version_major: 5 })));
+	}
+}
@@ -1,9 +1,13 @@
-use crate::oxcdata::{date_opt, complete_utf16le_string};
+use crate::oxcdata::{date_opt, filetime, complete_utf16le_string};
+use crate::error::{CfbError, CfbResult};
 
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::fmt::{Formatter, Result, Display};
+use std::fmt::{self, Formatter, Result, Display};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
 
 use chrono::{DateTime, Utc};
 use nom::{
@@ -14,6 +18,7 @@ use nom::{
 };
 
 pub const ENTRY_SIZE: usize = 128;
+pub const MAX_NAME_LEN: usize = 31; // in UTF-16 code units, excluding terminator
 pub const OBJECT_UNKNOWN: u8 = 0x00;
 pub const OBJECT_STORAGE: u8 = 0x01; // folder
 pub const OBJECT_STREAM: u8 = 0x02; // file
@@ -23,7 +28,134 @@ pub const NOSTREAM: u32 = 0xFFFFFFFF;
 pub const COLOR_RED: u8 = 0x00;
 pub const COLOR_BLACK: u8 = 0x01;
 
+// Compares directory entry names as specified in [MS-CFB] 2.6.4: shorter names
+// sort first, equal-length names are compared by their uppercase UTF-16 code units.
+pub fn compare_names(a: &str, b: &str) -> Ordering {
+	let a_len = a.encode_utf16().count();
+	let b_len = b.encode_utf16().count();
+	a_len.cmp(&b_len).then_with(|| {
+		let a_upper: String = a.chars().map(simple_uppercase).collect();
+		let b_upper: String = b.chars().map(simple_uppercase).collect();
+		a_upper.encode_utf16().cmp(b_upper.encode_utf16())
+	})
+}
+
+fn simple_uppercase(c: char) -> char {
+	let mut upper = c.to_uppercase();
+	match (upper.next(), upper.next()) {
+		(Some(u), None) => u,
+		_ => c,
+	}
+}
+
+// Formats a CLSID in registry form, e.g. {00020D0B-0000-0000-C000-000000000046}.
+// The first three fields are stored little-endian.
+pub fn format_clsid(clsid: &[u8; 16]) -> String {
+	format!(
+		"{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}}}",
+		u32::from_le_bytes([clsid[0], clsid[1], clsid[2], clsid[3]]),
+		u16::from_le_bytes([clsid[4], clsid[5]]),
+		u16::from_le_bytes([clsid[6], clsid[7]]),
+		clsid[8], clsid[9], clsid[10], clsid[11], clsid[12], clsid[13], clsid[14], clsid[15],
+	)
+}
+
+// Parses a CLSID in the registry form written by format_clsid.
+pub fn parse_clsid(s: &str) -> Option<[u8; 16]> {
+	let fields: Vec<&str> = s.strip_prefix('{')?.strip_suffix('}')?.split('-').collect();
+	if fields.len() != 5 || fields.iter().zip([8, 4, 4, 4, 12]).any(|(field, len)| field.len() != len || !field.bytes().all(|b| b.is_ascii_hexdigit())) {
+		return None;
+	}
+	let mut clsid = [0u8; 16];
+	clsid[0..4].copy_from_slice(&u32::from_str_radix(fields[0], 16).ok()?.to_le_bytes());
+	clsid[4..6].copy_from_slice(&u16::from_str_radix(fields[1], 16).ok()?.to_le_bytes());
+	clsid[6..8].copy_from_slice(&u16::from_str_radix(fields[2], 16).ok()?.to_le_bytes());
+	let rest = [fields[3], fields[4]].concat();
+	for (i, byte) in clsid[8..].iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&rest[i * 2..i * 2 + 2], 16).ok()?;
+	}
+	Some(clsid)
+}
+
+// Directory entry name that compares, orders and hashes as specified in
+// [MS-CFB] 2.6.4, so that lookups are case-insensitive.
+#[derive(Clone, Default)]
+pub struct EntryName(String);
+
+impl EntryName {
+	pub fn new<S: Into<String>>(name: S) -> Self {
+		Self(name.into())
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	pub fn into_string(self) -> String {
+		self.0
+	}
+}
+
+impl From<&str> for EntryName {
+	fn from(name: &str) -> Self {
+		Self(name.to_string())
+	}
+}
+
+impl From<String> for EntryName {
+	fn from(name: String) -> Self {
+		Self(name)
+	}
+}
+
+impl AsRef<str> for EntryName {
+	fn as_ref(&self) -> &str {
+		&self.0
+	}
+}
+
+impl PartialEq for EntryName {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for EntryName {}
+
+impl PartialOrd for EntryName {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for EntryName {
+	fn cmp(&self, other: &Self) -> Ordering {
+		compare_names(&self.0, &other.0)
+	}
+}
+
+impl Hash for EntryName {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		for c in self.0.chars().map(simple_uppercase) {
+			c.hash(state);
+		}
+	}
+}
+
+impl fmt::Debug for EntryName {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		fmt::Debug::fmt(&self.0, f)
+	}
+}
+
+impl Display for EntryName {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		Display::fmt(&self.0, f)
+	}
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DirectoryEntry {
 	pub name: String,
 	pub object_type: u8,
@@ -37,7 +169,9 @@ pub struct DirectoryEntry {
 	pub modified_time: Option<DateTime<Utc>>,
 	pub starting_sector: u32,
 	pub stream_size: u64,
-	pub children: RefCell<BTreeMap<String, Rc<DirectoryEntry>>>,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	pub children: RefCell<BTreeMap<EntryName, Rc<DirectoryEntry>>>,
+	#[cfg_attr(feature = "serde", serde(skip))]
 	pub data: RefCell<Vec<u8>>,
 }
 
@@ -45,7 +179,7 @@ impl DirectoryEntry {
 	pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
 		let (input, name) = take(64u16)(input)?;
 		let (input, name_len) = le_u16(input)?;
-		let (_, name) = complete_utf16le_string(&name[..name_len as usize])?;
+		let (_, name) = complete_utf16le_string(&name[..(name_len as usize).min(name.len())])?;
 		let (input, object_type) = u8(input)?;
 		let (input, color_flag) = u8(input)?;
 		let (input, left_sibling_id) = le_u32(input)?;
@@ -74,6 +208,36 @@ impl DirectoryEntry {
 		}))
 	}
 
+	pub fn write_to<W: Write>(&self, writer: &mut W) -> CfbResult<()> {
+		let name: Vec<u16> = self.name.encode_utf16().collect();
+		if name.len() > MAX_NAME_LEN {
+			return Err(CfbError::NameTooLong { name: self.name.clone() });
+		}
+		let mut name_bytes = [0u8; 64];
+		for (i, c) in name.iter().enumerate() {
+			name_bytes[i * 2..i * 2 + 2].copy_from_slice(&c.to_le_bytes());
+		}
+		let name_len = if name.is_empty() { 0 } else { (name.len() as u16 + 1) * 2 };
+		writer.write_all(&name_bytes)?;
+		writer.write_all(&name_len.to_le_bytes())?;
+		writer.write_all(&[self.object_type, self.color_flag])?;
+		writer.write_all(&self.left_sibling_id.to_le_bytes())?;
+		writer.write_all(&self.right_sibling_id.to_le_bytes())?;
+		writer.write_all(&self.child_id.to_le_bytes())?;
+		writer.write_all(&self.clsid)?;
+		writer.write_all(&self.state_bits.to_le_bytes())?;
+		for time in [&self.creation_time, &self.modified_time] {
+			let filetime = match time {
+				Some(time) => filetime(time).ok_or_else(|| CfbError::TimestampOutOfRange { name: self.name.clone() })?,
+				None => 0,
+			};
+			writer.write_all(&filetime.to_le_bytes())?;
+		}
+		writer.write_all(&self.starting_sector.to_le_bytes())?;
+		writer.write_all(&self.stream_size.to_le_bytes())?;
+		Ok(())
+	}
+
 	fn list_children(&self, f: &mut Formatter<'_>, level: usize, expand: bool) -> Result {
 		let line_prefix = "\t".repeat(level);
 		for (name, child_entry) in self.children.borrow().iter() {
@@ -120,4 +284,271 @@ impl Default for DirectoryEntry {
 			data: RefCell::new(Vec::new()),
 		}
 	}
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DirectoryProblem {
+	// entry links to a directory entry ID that does not exist
+	InvalidId { id: u32, linked_id: u32 },
+	// entry has a color flag other than red or black
+	InvalidColor { id: u32, color_flag: u8 },
+	// red entry has a red left or right sibling
+	RedViolation { id: u32 },
+	// paths through the left and right siblings of entry pass different numbers of black entries
+	BlackHeightMismatch { id: u32 },
+	// entry is not ordered correctly relative to a sibling above it in the tree
+	OutOfOrder { id: u32, sibling_id: u32 },
+	// entry is linked from more than one place
+	SharedEntry { id: u32 },
+	// entry links back to one of the entries it is reachable from
+	Cycle { id: u32 },
+	// allocated entry is not reachable from the root storage
+	Orphan { id: u32 },
+}
+
+impl Display for DirectoryProblem {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		match self {
+			Self::InvalidId { id, linked_id } => write!(f, "directory entry {} links to invalid ID {}", id, linked_id),
+			Self::InvalidColor { id, color_flag } => write!(f, "directory entry {} has invalid color flag {:#04X}", id, color_flag),
+			Self::RedViolation { id } => write!(f, "red directory entry {} has a red sibling", id),
+			Self::BlackHeightMismatch { id } => write!(f, "sibling subtrees of directory entry {} have different black heights", id),
+			Self::OutOfOrder { id, sibling_id } => write!(f, "directory entry {} is out of order relative to sibling {}", id, sibling_id),
+			Self::SharedEntry { id } => write!(f, "directory entry {} is linked more than once", id),
+			Self::Cycle { id } => write!(f, "directory entry {} is part of a cycle", id),
+			Self::Orphan { id } => write!(f, "directory entry {} is not reachable from the root storage", id),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ValidateFrame {
+	id: u32,
+	lower: Option<u32>, // sibling the entry must sort after
+	upper: Option<u32>, // sibling the entry must sort before
+	stage: u8,
+}
+
+// Walks the directory from the root entry without recursion, reporting every
+// violation of the red-black tree rules in [MS-CFB] 2.6.4 and any entry that
+// is unreachable, reachable more than once or part of a cycle.
+pub fn validate_tree(entries: &[Rc<DirectoryEntry>]) -> Vec<DirectoryProblem> {
+	const UNVISITED: u8 = 0;
+	const ON_PATH: u8 = 1;
+	const DONE: u8 = 2;
+
+	let mut problems = Vec::new();
+	if entries.is_empty() {
+		return problems;
+	}
+	let mut state = vec![UNVISITED; entries.len()];
+	let mut black_height: Vec<Option<u32>> = vec![None; entries.len()];
+	let mut stack = vec![ValidateFrame { id: 0, lower: None, upper: None, stage: 0 }];
+	while let Some(frame) = stack.last_mut() {
+		let id = frame.id;
+		let entry = &entries[id as usize];
+		let (linked_id, lower, upper) = match frame.stage {
+			0 => {
+				state[id as usize] = ON_PATH;
+				if entry.color_flag != COLOR_RED && entry.color_flag != COLOR_BLACK {
+					problems.push(DirectoryProblem::InvalidColor { id, color_flag: entry.color_flag });
+				}
+				let name = EntryName::from(entry.name.as_str());
+				for sibling_id in frame.lower.into_iter().filter(|&sibling_id| EntryName::from(entries[sibling_id as usize].name.as_str()) >= name) {
+					problems.push(DirectoryProblem::OutOfOrder { id, sibling_id });
+				}
+				for sibling_id in frame.upper.into_iter().filter(|&sibling_id| EntryName::from(entries[sibling_id as usize].name.as_str()) <= name) {
+					problems.push(DirectoryProblem::OutOfOrder { id, sibling_id });
+				}
+				(entry.left_sibling_id, frame.lower, Some(id))
+			}
+			1 => (entry.right_sibling_id, Some(id), frame.upper),
+			2 => (entry.child_id, None, None),
+			_ => {
+				let height = |sibling_id: u32| if sibling_id == NOSTREAM { Some(0) } else { black_height.get(sibling_id as usize).copied().flatten() };
+				let is_red = |sibling_id: u32| entries.get(sibling_id as usize).is_some_and(|sibling| sibling.color_flag == COLOR_RED);
+				let (left_height, right_height) = (height(entry.left_sibling_id), height(entry.right_sibling_id));
+				if let (Some(left_height), Some(right_height)) = (left_height, right_height) {
+					if left_height != right_height {
+						problems.push(DirectoryProblem::BlackHeightMismatch { id });
+					}
+				}
+				if entry.color_flag == COLOR_RED && (is_red(entry.left_sibling_id) || is_red(entry.right_sibling_id)) {
+					problems.push(DirectoryProblem::RedViolation { id });
+				}
+				let own_height = if entry.color_flag == COLOR_RED { 0 } else { 1 };
+				black_height[id as usize] = Some(left_height.max(right_height).unwrap_or(0) + own_height);
+				state[id as usize] = DONE;
+				stack.pop();
+				continue
+			}
+		};
+		frame.stage += 1;
+		if linked_id == NOSTREAM {
+			continue
+		}
+		match state.get(linked_id as usize) {
+			None => problems.push(DirectoryProblem::InvalidId { id, linked_id }),
+			Some(&ON_PATH) => problems.push(DirectoryProblem::Cycle { id: linked_id }),
+			Some(&DONE) => problems.push(DirectoryProblem::SharedEntry { id: linked_id }),
+			Some(_) => stack.push(ValidateFrame { id: linked_id, lower, upper, stage: 0 }),
+		}
+	}
+
+	for (id, entry) in entries.iter().enumerate() {
+		if state[id] == UNVISITED && entry.object_type != OBJECT_UNKNOWN {
+			problems.push(DirectoryProblem::Orphan { id: id as u32 });
+		}
+	}
+	problems
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use chrono::{Duration, TimeZone};
+
+	fn write_and_parse(entry: &DirectoryEntry) -> CfbResult<DirectoryEntry> {
+		let mut buf = Vec::new();
+		entry.write_to(&mut buf)?;
+		Ok(DirectoryEntry::parse(&buf).expect("written entry parses").1)
+	}
+
+	#[test]
+	fn timestamps_keep_100ns_precision() {
+		let creation_time = Utc.timestamp_opt(1_700_000_000, 123_456_700).unwrap();
+		let modified_time = Utc.with_ymd_and_hms(1601, 1, 1, 0, 0, 0).unwrap() + Duration::nanoseconds(100);
+		let entry = DirectoryEntry {
+			name: "Stream".to_string(),
+			object_type: OBJECT_STREAM,
+			creation_time: Some(creation_time),
+			modified_time: Some(modified_time),
+			..Default::default()
+		};
+		let parsed = write_and_parse(&entry).unwrap();
+		assert_eq!(parsed.creation_time, Some(creation_time));
+		assert_eq!(parsed.modified_time, Some(modified_time));
+	}
+
+	#[test]
+	fn timestamp_before_1601_is_rejected() {
+		let entry = DirectoryEntry {
+			name: "Stream".to_string(),
+			creation_time: Some(Utc.with_ymd_and_hms(1600, 12, 31, 23, 59, 59).unwrap()),
+			..Default::default()
+		};
+		assert!(matches!(write_and_parse(&entry), Err(CfbError::TimestampOutOfRange { .. })));
+	}
+
+	fn entry(name: &str, object_type: u8, color_flag: u8, left_sibling_id: u32, right_sibling_id: u32, child_id: u32) -> Rc<DirectoryEntry> {
+		Rc::new(DirectoryEntry {
+			name: name.to_string(),
+			object_type,
+			color_flag,
+			left_sibling_id,
+			right_sibling_id,
+			child_id,
+			..Default::default()
+		})
+	}
+
+	fn root(child_id: u32) -> Rc<DirectoryEntry> {
+		entry("Root Entry", OBJECT_ROOT_STORAGE, COLOR_BLACK, NOSTREAM, NOSTREAM, child_id)
+	}
+
+	fn stream(name: &str, color_flag: u8, left_sibling_id: u32, right_sibling_id: u32) -> Rc<DirectoryEntry> {
+		entry(name, OBJECT_STREAM, color_flag, left_sibling_id, right_sibling_id, NOSTREAM)
+	}
+
+	#[test]
+	fn valid_tree_has_no_problems() {
+		let entries = [root(1), stream("B", COLOR_BLACK, 2, 3), stream("A", COLOR_RED, NOSTREAM, NOSTREAM), stream("CC", COLOR_RED, NOSTREAM, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), Vec::new());
+	}
+
+	#[test]
+	fn invalid_id() {
+		let entries = [root(1), stream("A", COLOR_BLACK, 5, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::InvalidId { id: 1, linked_id: 5 }]);
+	}
+
+	#[test]
+	fn invalid_color() {
+		let entries = [root(1), stream("A", 7, NOSTREAM, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::InvalidColor { id: 1, color_flag: 7 }]);
+	}
+
+	#[test]
+	fn red_violation() {
+		let entries = [root(1), stream("B", COLOR_RED, 2, NOSTREAM), stream("A", COLOR_RED, NOSTREAM, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::RedViolation { id: 1 }]);
+	}
+
+	#[test]
+	fn black_height_mismatch() {
+		let entries = [root(1), stream("B", COLOR_BLACK, 2, NOSTREAM), stream("A", COLOR_BLACK, NOSTREAM, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::BlackHeightMismatch { id: 1 }]);
+	}
+
+	#[test]
+	fn out_of_order() {
+		// "B" is the left sibling of "A" but sorts after it
+		let entries = [root(1), stream("A", COLOR_BLACK, 2, NOSTREAM), stream("B", COLOR_RED, NOSTREAM, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::OutOfOrder { id: 2, sibling_id: 1 }]);
+	}
+
+	#[test]
+	fn shared_entry() {
+		let entries = [root(1), stream("B", COLOR_BLACK, 2, 2), stream("A", COLOR_RED, NOSTREAM, NOSTREAM)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::SharedEntry { id: 2 }]);
+	}
+
+	#[test]
+	fn cycle() {
+		let entries = [root(1), entry("A", OBJECT_STORAGE, COLOR_BLACK, NOSTREAM, NOSTREAM, 1)];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::Cycle { id: 1 }]);
+	}
+
+	#[test]
+	fn orphan() {
+		// unused entries have the unknown object type and are not orphans
+		let entries = [root(1), stream("A", COLOR_BLACK, NOSTREAM, NOSTREAM), stream("B", COLOR_BLACK, NOSTREAM, NOSTREAM), Rc::new(DirectoryEntry::default())];
+		assert_eq!(validate_tree(&entries), vec![DirectoryProblem::Orphan { id: 2 }]);
+	}
+
+	#[test]
+	fn shorter_names_sort_first() {
+		assert_eq!(compare_names("Z", "AA"), Ordering::Less);
+		assert_eq!(compare_names("AAA", "ZZ"), Ordering::Greater);
+		// length is counted in UTF-16 code units, so a surrogate pair is two
+		assert_eq!(compare_names("\u{1F600}", "AAA"), Ordering::Less);
+		assert_eq!(compare_names("\u{1F600}", "ZZ"), Ordering::Greater);
+	}
+
+	#[test]
+	fn equal_length_names_compare_uppercased() {
+		assert_eq!(compare_names("abc", "ABC"), Ordering::Equal);
+		assert_eq!(compare_names("abc", "ABD"), Ordering::Less);
+		assert_eq!(compare_names("\u{E9}t\u{E9}", "\u{C9}T\u{C9}"), Ordering::Equal);
+		// '_' sorts between upper and lower case letters, so uppercasing matters
+		assert_eq!(compare_names("a", "_"), Ordering::Less);
+		// characters whose uppercase form is longer than one character are kept
+		assert_eq!(compare_names("\u{DF}", "S"), Ordering::Greater);
+	}
+
+	#[test]
+	fn entry_names_are_case_insensitive() {
+		assert_eq!(EntryName::from("__Properties_Version1.0"), EntryName::from("__properties_version1.0"));
+		let names: std::collections::HashSet<EntryName> = ["Stream", "STREAM", "stream"].into_iter().map(EntryName::from).collect();
+		assert_eq!(names.len(), 1);
+		let sorted: Vec<String> = ["bb", "A", "c", "AAA"].into_iter()
+			.map(EntryName::from)
+			.collect::<std::collections::BTreeSet<_>>()
+			.into_iter()
+			.map(EntryName::into_string)
+			.collect();
+		assert_eq!(sorted, ["A", "c", "bb", "AAA"]);
+	}
+}
@@ -0,0 +1,795 @@
+use crate::builder;
+use crate::cfb::{CompoundFile, CompoundFileHeader, V3};
+use crate::dir::{self, DirectoryEntry, EntryName};
+use crate::error::{CfbError, CfbResult};
+use crate::fat;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+// Edits a compound file in place. Changes to the tree and to stream contents
+// are kept in memory until commit, which writes only the sectors that changed
+// and reuses free sectors before growing the file. Sectors freed by a change
+// are only reused by a later commit, and the directory and FAT are written
+// after the stream contents, so the old tree stays intact until then. Sectors,
+// directory entries and header fields that are not affected by a change are
+// left as they are.
+#[derive(Debug)]
+pub struct CompoundFileEditor<F> {
+	file: F,
+	header: CompoundFileHeader,
+	difat: Vec<u32>,
+	difat_sectors: Vec<u32>,
+	fat: Vec<u32>,
+	minifat: Vec<u32>,
+	minifat_chain: Vec<u32>,
+	dir_chain: Vec<u32>,
+	mini_stream_chain: Vec<u32>,
+	entries: Vec<DirectoryEntry>,
+	children: Vec<BTreeMap<EntryName, u32>>,
+	free_entries: BTreeSet<u32>,
+	data: BTreeMap<u32, Vec<u8>>, // stream contents to be written, by directory entry ID
+	dirty_storages: BTreeSet<u32>, // storages whose sibling tree must be rebuilt
+	dirty_entries: BTreeSet<u32>,
+	dirty_fat: BTreeSet<usize>, // indices into difat
+	dirty_minifat: BTreeSet<usize>, // indices into minifat_chain
+	dirty_difat: BTreeSet<usize>, // indices into difat_sectors
+	dirty_header: bool,
+	free_hint: usize, // no FAT entry below this one is free
+	mini_free_hint: usize, // no MiniFAT entry below this one is free
+	freed: BTreeSet<u32>, // sectors to mark free once the commit has placed new contents
+	mini_freed: BTreeSet<u32>,
+}
+
+impl<F: Read + Write + Seek> CompoundFileEditor<F> {
+	pub fn open(mut file: F) -> CfbResult<Self> {
+		file.seek(SeekFrom::Start(0))?;
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut file)?;
+		let ids: HashMap<*const DirectoryEntry, u32> = cfb.dirs.iter()
+			.enumerate()
+			.map(|(id, entry)| (Rc::as_ptr(entry), id as u32))
+			.collect();
+		let children: Vec<BTreeMap<EntryName, u32>> = cfb.dirs.iter()
+			.map(|entry| entry.children.borrow().iter()
+				.filter_map(|(name, child)| Some((name.clone(), *ids.get(&Rc::as_ptr(child))?)))
+				.collect())
+			.collect();
+
+		// unallocated entries may be reused, unless something still links to them
+		let linked: BTreeSet<u32> = children.iter().flat_map(|children| children.values().copied()).collect();
+		let free_entries = cfb.dirs.iter().enumerate()
+			.filter(|&(id, entry)| id != 0 && entry.object_type == dir::OBJECT_UNKNOWN && !linked.contains(&(id as u32)))
+			.map(|(id, _)| id as u32)
+			.collect();
+
+		let entries: Vec<DirectoryEntry> = cfb.dirs.iter()
+			.map(|entry| DirectoryEntry { children: RefCell::default(), ..DirectoryEntry::clone(entry) })
+			.collect();
+		let minifat_chain = fat::get_chain(&cfb.fat, cfb.header.minifat_first_sector)?;
+		let dir_chain = fat::get_chain(&cfb.fat, cfb.header.dir_first_sector)?;
+		let mini_stream_chain = fat::get_chain(&cfb.fat, entries[0].starting_sector)?;
+
+		Ok(Self {
+			file,
+			header: cfb.header,
+			difat: cfb.difat,
+			difat_sectors: cfb.difat_sectors,
+			fat: cfb.fat,
+			minifat: cfb.minifat,
+			minifat_chain,
+			dir_chain,
+			mini_stream_chain,
+			entries,
+			children,
+			free_entries,
+			data: BTreeMap::new(),
+			dirty_storages: BTreeSet::new(),
+			dirty_entries: BTreeSet::new(),
+			dirty_fat: BTreeSet::new(),
+			dirty_minifat: BTreeSet::new(),
+			dirty_difat: BTreeSet::new(),
+			dirty_header: false,
+			free_hint: 0,
+			mini_free_hint: 0,
+			freed: BTreeSet::new(),
+			mini_freed: BTreeSet::new(),
+		})
+	}
+
+	pub fn into_inner(self) -> F {
+		self.file
+	}
+
+	fn find(&self, path: &str) -> CfbResult<u32> {
+		let mut id = 0;
+		let mut entry_path = String::new();
+		for component in builder::split_path(path) {
+			if self.entries[id as usize].object_type == dir::OBJECT_STREAM {
+				return Err(CfbError::NotAStorage { path: entry_path });
+			}
+			entry_path.push('/');
+			entry_path.push_str(component);
+			id = *self.children[id as usize].get(&EntryName::from(component)).ok_or_else(|| CfbError::MissingEntry { path: entry_path.clone() })?;
+		}
+		Ok(id)
+	}
+
+	// Returns the ID of the storage holding path and the name of path in it.
+	fn find_parent<'a>(&self, path: &'a str) -> CfbResult<(u32, &'a str)> {
+		let (parent_path, name) = path.trim_end_matches('/').rsplit_once('/').unwrap_or(("", path));
+		if name.is_empty() {
+			return Err(CfbError::InvalidName { name: path.to_string() });
+		}
+		let parent = self.find(parent_path)?;
+		if self.entries[parent as usize].object_type == dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStorage { path: parent_path.to_string() });
+		}
+		Ok((parent, name))
+	}
+
+	fn find_stream(&self, path: &str) -> CfbResult<u32> {
+		let id = self.find(path)?;
+		if self.entries[id as usize].object_type != dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStream { path: path.to_string() });
+		}
+		Ok(id)
+	}
+
+	pub fn exists(&self, path: &str) -> bool {
+		self.find(path).is_ok()
+	}
+
+	fn insert(&mut self, path: &str, entry: DirectoryEntry) -> CfbResult<u32> {
+		let (parent, name) = self.find_parent(path)?;
+		builder::validate_name(name)?;
+		if self.children[parent as usize].contains_key(&EntryName::from(name)) {
+			return Err(CfbError::DuplicateName { name: name.to_string() });
+		}
+		let id = self.allocate_entry();
+		self.entries[id as usize] = DirectoryEntry { name: name.to_string(), ..entry };
+		self.children[parent as usize].insert(EntryName::from(name), id);
+		self.dirty_storages.insert(parent);
+		self.dirty_entries.insert(id);
+		Ok(id)
+	}
+
+	fn allocate_entry(&mut self) -> u32 {
+		if let Some(id) = self.free_entries.pop_first() {
+			return id;
+		}
+		// the directory grows by a whole sector of unallocated entries
+		let start = self.entries.len();
+		let end = start + self.header.sector_size() / dir::ENTRY_SIZE;
+		self.entries.resize_with(end, DirectoryEntry::default);
+		self.children.resize_with(end, BTreeMap::new);
+		self.dirty_entries.extend(start as u32..end as u32);
+		self.free_entries.extend(start as u32 + 1..end as u32);
+		start as u32
+	}
+
+	pub fn create_storage(&mut self, path: &str) -> CfbResult<&mut Self> {
+		self.insert(path, DirectoryEntry { object_type: dir::OBJECT_STORAGE, color_flag: dir::COLOR_BLACK, ..Default::default() })?;
+		Ok(self)
+	}
+
+	pub fn create_stream(&mut self, path: &str, data: Vec<u8>) -> CfbResult<&mut Self> {
+		self.check_stream_size(path, &data)?;
+		let id = self.insert(path, DirectoryEntry {
+			object_type: dir::OBJECT_STREAM,
+			color_flag: dir::COLOR_BLACK,
+			starting_sector: fat::ENDOFCHAIN,
+			stream_size: data.len() as u64,
+			..Default::default()
+		})?;
+		self.data.insert(id, data);
+		Ok(self)
+	}
+
+	fn check_stream_size(&self, path: &str, data: &[u8]) -> CfbResult<()> {
+		if self.header.version_major == V3 && data.len() > u32::MAX as usize {
+			return Err(CfbError::StreamTooLarge { name: path.to_string() });
+		}
+		Ok(())
+	}
+
+	// Replaces the contents of a stream. The old sectors are freed, and the new
+	// contents are placed in the FAT or MiniFAT depending on their size.
+	pub fn replace_stream(&mut self, path: &str, data: Vec<u8>) -> CfbResult<&mut Self> {
+		let id = self.find_stream(path)?;
+		self.check_stream_size(path, &data)?;
+		if !self.data.contains_key(&id) {
+			self.free_stream(id)?;
+		}
+		self.entries[id as usize].stream_size = data.len() as u64;
+		self.dirty_entries.insert(id);
+		self.data.insert(id, data);
+		Ok(self)
+	}
+
+	// Removes a stream, or a storage along with everything below it.
+	pub fn remove(&mut self, path: &str) -> CfbResult<&mut Self> {
+		let (parent, name) = self.find_parent(path)?;
+		let id = self.find(path)?;
+
+		// find every sector to free first, so a broken chain leaves nothing changed
+		let mut ids = Vec::new();
+		let mut queue = vec![id];
+		while let Some(id) = queue.pop() {
+			queue.extend(self.children[id as usize].values().copied());
+			ids.push(id);
+		}
+		// streams with pending contents had their sectors freed already
+		let chains = ids.iter()
+			.filter(|id| !self.data.contains_key(id))
+			.map(|&id| self.stream_chain(id))
+			.collect::<CfbResult<Vec<_>>>()?;
+
+		self.children[parent as usize].remove(&EntryName::from(name));
+		self.dirty_storages.insert(parent);
+		for &id in &ids {
+			self.children[id as usize].clear();
+			self.data.remove(&id);
+			self.entries[id as usize] = DirectoryEntry::default();
+			self.dirty_storages.remove(&id);
+			self.dirty_entries.insert(id);
+			self.free_entries.insert(id);
+		}
+		for (mini, chain) in chains {
+			self.free_chain(mini, &chain);
+		}
+		Ok(self)
+	}
+
+	pub fn rename(&mut self, path: &str, name: &str) -> CfbResult<&mut Self> {
+		let (parent, old_name) = self.find_parent(path)?;
+		let id = self.find(path)?;
+		if name.is_empty() {
+			return Err(CfbError::InvalidName { name: name.to_string() });
+		}
+		builder::validate_name(name)?;
+		let children = &mut self.children[parent as usize];
+		if children.get(&EntryName::from(name)).is_some_and(|&other| other != id) {
+			return Err(CfbError::DuplicateName { name: name.to_string() });
+		}
+		children.remove(&EntryName::from(old_name));
+		children.insert(EntryName::from(name), id);
+		self.entries[id as usize].name = name.to_string();
+		self.dirty_storages.insert(parent);
+		self.dirty_entries.insert(id);
+		Ok(self)
+	}
+
+	// Returns the current contents of a stream, including changes that are
+	// not committed yet.
+	pub fn read_stream(&mut self, path: &str) -> CfbResult<Vec<u8>> {
+		let id = self.find_stream(path)?;
+		if let Some(data) = self.data.get(&id) {
+			return Ok(data.clone());
+		}
+		let entry = &self.entries[id as usize];
+		let size = entry.stream_size as usize;
+		let mut data = Vec::with_capacity(size);
+		if size == 0 {
+			return Ok(data);
+		}
+		let (chunk_size, offsets) = if entry.stream_size < self.header.mini_stream_cutoff_size as u64 {
+			let offsets = fat::get_chain(&self.minifat, entry.starting_sector)?.into_iter()
+				.map(|sector| self.mini_sector_offset(sector))
+				.collect::<CfbResult<Vec<u64>>>()?;
+			(fat::MINIFAT_SECTOR_SIZE, offsets)
+		} else {
+			let offsets = fat::get_chain(&self.fat, entry.starting_sector)?.into_iter()
+				.map(|sector| self.header.sector_offset(sector) as u64)
+				.collect();
+			(self.header.sector_size(), offsets)
+		};
+		if offsets.len() < size.div_ceil(chunk_size) {
+			return Err(CfbError::StreamSizeMismatch { id, size: size as u64, sectors: offsets.len() });
+		}
+		for offset in offsets {
+			let len = chunk_size.min(size - data.len());
+			if len == 0 {
+				break;
+			}
+			let start = data.len();
+			data.resize(start + len, 0);
+			self.file.seek(SeekFrom::Start(offset))?;
+			self.file.read_exact(&mut data[start..])?;
+		}
+		Ok(data)
+	}
+
+	fn mini_sector_offset(&self, sector: u32) -> CfbResult<u64> {
+		let sector_size = self.header.sector_size();
+		let mini_stream_offset = sector as usize * fat::MINIFAT_SECTOR_SIZE;
+		let mini_stream_sector = self.mini_stream_chain.get(mini_stream_offset / sector_size)
+			.ok_or(CfbError::MiniSectorOutOfRange { sector })?;
+		Ok((self.header.sector_offset(*mini_stream_sector) + mini_stream_offset % sector_size) as u64)
+	}
+
+	fn set_fat(&mut self, sector: u32, value: u32) {
+		self.fat[sector as usize] = value;
+		self.dirty_fat.insert(sector as usize / (self.header.sector_size() / 4));
+		if value == fat::FREESECT {
+			self.free_hint = self.free_hint.min(sector as usize);
+		}
+	}
+
+	fn set_minifat(&mut self, sector: u32, value: u32) {
+		self.minifat[sector as usize] = value;
+		self.dirty_minifat.insert(sector as usize / (self.header.sector_size() / 4));
+		if value == fat::FREESECT {
+			self.mini_free_hint = self.mini_free_hint.min(sector as usize);
+		}
+	}
+
+	// Returns whether the sectors of an entry are mini sectors, and their chain.
+	fn stream_chain(&self, id: u32) -> CfbResult<(bool, Vec<u32>)> {
+		let entry = &self.entries[id as usize];
+		if entry.object_type != dir::OBJECT_STREAM || entry.stream_size == 0 {
+			return Ok((false, Vec::new()));
+		}
+		if entry.stream_size < self.header.mini_stream_cutoff_size as u64 {
+			Ok((true, fat::get_chain(&self.minifat, entry.starting_sector)?))
+		} else {
+			Ok((false, fat::get_chain(&self.fat, entry.starting_sector)?))
+		}
+	}
+
+	// Queues the sectors of a chain to be freed by the next commit. Until then
+	// they are not reused, as the directory on disk still points to them.
+	fn free_chain(&mut self, mini: bool, chain: &[u32]) {
+		if mini {
+			self.mini_freed.extend(chain);
+		} else {
+			self.freed.extend(chain);
+		}
+	}
+
+	// Frees the sectors of a stream, leaving their contents in place.
+	fn free_stream(&mut self, id: u32) -> CfbResult<()> {
+		let (mini, chain) = self.stream_chain(id)?;
+		self.free_chain(mini, &chain);
+		let entry = &mut self.entries[id as usize];
+		entry.starting_sector = fat::ENDOFCHAIN;
+		entry.stream_size = 0;
+		self.dirty_entries.insert(id);
+		Ok(())
+	}
+
+	// Takes the lowest free sector, growing the FAT when it has none, and marks
+	// it as the end of a chain.
+	fn allocate_sector(&mut self) -> CfbResult<u32> {
+		loop {
+			if let Some(i) = self.fat[self.free_hint.min(self.fat.len())..].iter().position(|&value| value == fat::FREESECT) {
+				let sector = self.free_hint + i;
+				if sector > fat::MAXREGSECT as usize {
+					return Err(CfbError::TooManySectors);
+				}
+				self.free_hint = sector + 1;
+				self.set_fat(sector as u32, fat::ENDOFCHAIN);
+				return Ok(sector as u32);
+			}
+			self.free_hint = self.fat.len();
+			self.grow_fat()?;
+		}
+	}
+
+	// Adds a FAT sector, which holds its own FATSECT entry, and a DIFAT sector
+	// when the header and the existing DIFAT sectors are full.
+	fn grow_fat(&mut self) -> CfbResult<()> {
+		let fat_entries_per_sector = self.header.sector_size() / 4;
+		let sector = self.fat.len();
+		if sector > fat::MAXREGSECT as usize {
+			return Err(CfbError::TooManySectors);
+		}
+		self.fat.resize(sector + fat_entries_per_sector, fat::FREESECT);
+		self.set_fat(sector as u32, fat::FATSECT);
+		self.difat.push(sector as u32);
+		let index = self.difat.len() - 1;
+		if let Some(entry) = self.header.difat.get_mut(index) {
+			*entry = sector as u32;
+		} else {
+			let difat_index = (index - self.header.difat.len()) / (fat_entries_per_sector - 1);
+			if difat_index == self.difat_sectors.len() {
+				let difat_sector = self.allocate_sector()?;
+				self.set_fat(difat_sector, fat::DIFSECT);
+				match self.difat_sectors.last() {
+					Some(_) => { self.dirty_difat.insert(difat_index - 1); }
+					None => self.header.difat_first_sector = difat_sector,
+				}
+				self.difat_sectors.push(difat_sector);
+				self.header.difat_sectors += 1;
+			}
+			self.dirty_difat.insert(difat_index);
+		}
+		self.header.fat_sectors += 1;
+		self.dirty_header = true;
+		Ok(())
+	}
+
+	fn allocate_chain(&mut self, count: usize) -> CfbResult<Vec<u32>> {
+		let mut chain: Vec<u32> = Vec::with_capacity(count);
+		for _ in 0..count {
+			let sector = self.allocate_sector()?;
+			if let Some(&last) = chain.last() {
+				self.set_fat(last, sector);
+			}
+			chain.push(sector);
+		}
+		Ok(chain)
+	}
+
+	// Takes the lowest free mini sector, growing the MiniFAT and the mini stream
+	// as needed, and marks it as the end of a chain.
+	fn allocate_mini_sector(&mut self) -> CfbResult<u32> {
+		loop {
+			if let Some(i) = self.minifat[self.mini_free_hint.min(self.minifat.len())..].iter().position(|&value| value == fat::FREESECT) {
+				let sector = (self.mini_free_hint + i) as u32;
+				if sector > fat::MAXREGSECT {
+					return Err(CfbError::TooManySectors);
+				}
+				self.mini_free_hint = sector as usize + 1;
+				self.set_minifat(sector, fat::ENDOFCHAIN);
+				self.grow_mini_stream(sector)?;
+				return Ok(sector);
+			}
+			self.mini_free_hint = self.minifat.len();
+
+			// add a MiniFAT sector
+			let sector = self.allocate_sector()?;
+			match self.minifat_chain.last() {
+				Some(&last) => self.set_fat(last, sector),
+				None => self.header.minifat_first_sector = sector,
+			}
+			self.minifat_chain.push(sector);
+			self.minifat.resize(self.minifat.len() + self.header.sector_size() / 4, fat::FREESECT);
+			self.dirty_minifat.insert(self.minifat_chain.len() - 1);
+			self.header.minifat_sectors += 1;
+			self.dirty_header = true;
+		}
+	}
+
+	// Makes the mini stream long enough to hold mini sector.
+	fn grow_mini_stream(&mut self, sector: u32) -> CfbResult<()> {
+		let sector_size = self.header.sector_size();
+		let size = (sector as u64 + 1) * fat::MINIFAT_SECTOR_SIZE as u64;
+		if self.entries[0].stream_size < size {
+			self.entries[0].stream_size = size;
+			self.dirty_entries.insert(0);
+		}
+		while ((self.mini_stream_chain.len() * sector_size) as u64) < size {
+			let new_sector = self.allocate_sector()?;
+			match self.mini_stream_chain.last() {
+				Some(&last) => self.set_fat(last, new_sector),
+				None => {
+					self.entries[0].starting_sector = new_sector;
+					self.dirty_entries.insert(0);
+				}
+			}
+			self.mini_stream_chain.push(new_sector);
+			// mini sectors that are not allocated yet should not hold stale data
+			self.write_at(self.header.sector_offset(new_sector) as u64, &vec![0u8; sector_size])?;
+		}
+		Ok(())
+	}
+
+	fn write_at(&mut self, offset: u64, bytes: &[u8]) -> CfbResult<()> {
+		self.file.seek(SeekFrom::Start(offset))?;
+		self.file.write_all(bytes)?;
+		Ok(())
+	}
+
+	fn write_stream(&mut self, id: u32, data: &[u8]) -> CfbResult<()> {
+		let sector_size = self.header.sector_size();
+		let starting_sector = if data.is_empty() {
+			fat::ENDOFCHAIN
+		} else if (data.len() as u64) < self.header.mini_stream_cutoff_size as u64 {
+			let mut chain: Vec<u32> = Vec::with_capacity(data.len().div_ceil(fat::MINIFAT_SECTOR_SIZE));
+			for chunk in data.chunks(fat::MINIFAT_SECTOR_SIZE) {
+				let sector = self.allocate_mini_sector()?;
+				if let Some(&last) = chain.last() {
+					self.set_minifat(last, sector);
+				}
+				chain.push(sector);
+				let mut bytes = chunk.to_vec();
+				bytes.resize(fat::MINIFAT_SECTOR_SIZE, 0);
+				self.write_at(self.mini_sector_offset(sector)?, &bytes)?;
+			}
+			chain[0]
+		} else {
+			let chain = self.allocate_chain(data.len().div_ceil(sector_size))?;
+			for (&sector, chunk) in chain.iter().zip(data.chunks(sector_size)) {
+				let mut bytes = chunk.to_vec();
+				bytes.resize(sector_size, 0);
+				self.write_at(self.header.sector_offset(sector) as u64, &bytes)?;
+			}
+			chain[0]
+		};
+		let entry = &mut self.entries[id as usize];
+		entry.starting_sector = starting_sector;
+		entry.stream_size = data.len() as u64;
+		self.dirty_entries.insert(id);
+		Ok(())
+	}
+
+	fn write_entries(&mut self, sector: u32, entries: &[u32]) -> CfbResult<()> {
+		let mut bytes = Vec::with_capacity(self.header.sector_size());
+		for entry in entries {
+			bytes.extend_from_slice(&entry.to_le_bytes());
+		}
+		self.write_at(self.header.sector_offset(sector) as u64, &bytes)
+	}
+
+	// Writes all changes to the file.
+	pub fn commit(&mut self) -> CfbResult<()> {
+		let sector_size = self.header.sector_size();
+		let fat_entries_per_sector = sector_size / 4;
+
+		// place new stream contents
+		for (id, data) in std::mem::take(&mut self.data) {
+			self.write_stream(id, &data)?;
+		}
+
+		// relink the children of every storage whose children changed
+		for id in std::mem::take(&mut self.dirty_storages) {
+			let child_ids: Vec<u32> = self.children[id as usize].values().copied().collect();
+			let red_depth = (child_ids.len() as u32 + 1).ilog2();
+			self.entries[id as usize].child_id = builder::build_sibling_tree(&mut self.entries, &child_ids, 0, red_depth);
+			self.dirty_entries.insert(id);
+			self.dirty_entries.extend(child_ids);
+		}
+
+		// grow the directory to hold every entry
+		let dir_sector_count = (self.entries.len() * dir::ENTRY_SIZE).div_ceil(sector_size);
+		while self.dir_chain.len() < dir_sector_count {
+			let sector = self.allocate_sector()?;
+			match self.dir_chain.last() {
+				Some(&last) => self.set_fat(last, sector),
+				None => self.header.dir_first_sector = sector,
+			}
+			self.dir_chain.push(sector);
+			if self.header.version_major != V3 {
+				self.header.dir_sectors += 1;
+			}
+			self.dirty_header = true;
+		}
+
+		// every sector is allocated now, so freed sectors can be released
+		for sector in std::mem::take(&mut self.freed) {
+			self.set_fat(sector, fat::FREESECT);
+		}
+		for sector in std::mem::take(&mut self.mini_freed) {
+			self.set_minifat(sector, fat::FREESECT);
+		}
+
+		// write directory entries
+		let mut buf = Vec::with_capacity(dir::ENTRY_SIZE);
+		for id in std::mem::take(&mut self.dirty_entries) {
+			let offset = id as usize * dir::ENTRY_SIZE;
+			let sector = self.dir_chain[offset / sector_size];
+			buf.clear();
+			self.entries[id as usize].write_to(&mut buf)?;
+			self.write_at((self.header.sector_offset(sector) + offset % sector_size) as u64, &buf)?;
+		}
+
+		// write MiniFAT, FAT and DIFAT sectors
+		for index in std::mem::take(&mut self.dirty_minifat) {
+			let entries = self.minifat[index * fat_entries_per_sector..][..fat_entries_per_sector].to_vec();
+			self.write_entries(self.minifat_chain[index], &entries)?;
+		}
+		for index in std::mem::take(&mut self.dirty_fat) {
+			let entries = self.fat[index * fat_entries_per_sector..][..fat_entries_per_sector].to_vec();
+			self.write_entries(self.difat[index], &entries)?;
+		}
+		for index in std::mem::take(&mut self.dirty_difat) {
+			let start = self.header.difat.len() + index * (fat_entries_per_sector - 1);
+			let mut entries: Vec<u32> = self.difat[start.min(self.difat.len())..].iter().copied().take(fat_entries_per_sector - 1).collect();
+			entries.resize(fat_entries_per_sector - 1, fat::FREESECT);
+			entries.push(self.difat_sectors.get(index + 1).copied().unwrap_or(fat::ENDOFCHAIN));
+			self.write_entries(self.difat_sectors[index], &entries)?;
+		}
+
+		// write header, leaving the rest of its sector untouched
+		if std::mem::take(&mut self.dirty_header) {
+			buf.clear();
+			self.header.write_to(&mut buf)?;
+			self.write_at(0, &buf)?;
+		}
+
+		self.file.flush()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::builder::CompoundFileBuilder;
+
+	use std::io::Cursor;
+
+	fn sample_file() -> Cursor<Vec<u8>> {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap();
+		builder.create_stream("/Storage/Small", vec![1; 100]).unwrap();
+		builder.create_stream("/Storage/Large", vec![2; 5000]).unwrap();
+		builder.create_stream("/Other", vec![3; 300]).unwrap();
+		let mut cursor = Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		cursor.set_position(0);
+		cursor
+	}
+
+	// Commits the editor's changes, then parses and checks the result.
+	fn commit_and_parse(mut editor: CompoundFileEditor<Cursor<Vec<u8>>>) -> CompoundFile {
+		editor.commit().unwrap();
+		let mut file = editor.into_inner();
+		file.set_position(0);
+		let cfb = CompoundFile::parse_from_reader(&mut file).unwrap();
+		assert_eq!(cfb.check(), Vec::new());
+		cfb
+	}
+
+	fn stream_data(cfb: &CompoundFile, path: &str) -> Vec<u8> {
+		cfb.open_stream(path).unwrap().data.borrow().clone()
+	}
+
+	#[test]
+	fn grow_into_difat() {
+		let mut editor = CompoundFileEditor::open(sample_file()).unwrap();
+		// more FAT sectors than the 109 the header holds
+		editor.create_stream("/Huge", vec![4; 110 * 128 * 512]).unwrap();
+		let cfb = commit_and_parse(editor);
+		assert_eq!(cfb.difat_sectors.len(), 1);
+		assert_eq!(stream_data(&cfb, "/Huge"), vec![4; 110 * 128 * 512]);
+		assert_eq!(stream_data(&cfb, "/Storage/Large"), vec![2; 5000]);
+	}
+
+	#[test]
+	fn replace_across_mini_stream_cutoff() {
+		let mut editor = CompoundFileEditor::open(sample_file()).unwrap();
+		editor.replace_stream("/Storage/Small", vec![5; 6000]).unwrap();
+		editor.replace_stream("/Storage/Large", vec![6; 10]).unwrap();
+		let cfb = commit_and_parse(editor);
+		assert_eq!(stream_data(&cfb, "/Storage/Small"), vec![5; 6000]);
+		assert_eq!(stream_data(&cfb, "/Storage/Large"), vec![6; 10]);
+		assert_eq!(stream_data(&cfb, "/Other"), vec![3; 300]);
+	}
+
+	#[test]
+	fn remove_storage() {
+		let original = CompoundFile::parse_metadata_from_reader(&mut sample_file()).unwrap();
+		let large_chain = fat::get_chain(&original.fat, original.open_stream("/Storage/Large").unwrap().starting_sector).unwrap();
+		let mut editor = CompoundFileEditor::open(sample_file()).unwrap();
+		editor.replace_stream("/Storage/Small", vec![7; 50]).unwrap();
+		editor.remove("/Storage").unwrap();
+		assert!(!editor.exists("/Storage/Small"));
+		let cfb = commit_and_parse(editor);
+		assert!(!cfb.exists("/Storage"));
+		assert_eq!(stream_data(&cfb, "/Other"), vec![3; 300]);
+		assert!(large_chain.iter().all(|&sector| cfb.fat[sector as usize] == fat::FREESECT));
+	}
+
+	#[test]
+	fn remove_with_broken_chain_changes_nothing() {
+		let mut file = sample_file();
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut file).unwrap();
+		let sector = cfb.open_stream("/Storage/Large").unwrap().starting_sector;
+		let entries_per_sector = cfb.header.sector_size() / 4;
+		let offset = cfb.header.sector_offset(cfb.difat[sector as usize / entries_per_sector]) + sector as usize % entries_per_sector * 4;
+		file.get_mut()[offset..offset + 4].copy_from_slice(&sector.to_le_bytes());
+
+		let mut editor = CompoundFileEditor::open(file).unwrap();
+		let (fat, minifat, entries) = (editor.fat.clone(), editor.minifat.clone(), editor.entries.clone());
+		assert!(matches!(editor.remove("/Storage"), Err(CfbError::ChainCycle { .. })));
+		assert!(editor.exists("/Storage/Small"));
+		assert_eq!((editor.fat, editor.minifat, editor.entries), (fat, minifat, entries));
+	}
+
+	#[test]
+	fn rename() {
+		let mut editor = CompoundFileEditor::open(sample_file()).unwrap();
+		assert!(matches!(editor.rename("/Other", "storage"), Err(CfbError::DuplicateName { .. })));
+		editor.rename("/Storage/Large", "Renamed").unwrap();
+		editor.rename("/Storage", "Folder").unwrap();
+		let cfb = commit_and_parse(editor);
+		assert!(!cfb.exists("/Storage"));
+		assert_eq!(stream_data(&cfb, "/Folder/Renamed"), vec![2; 5000]);
+		assert_eq!(stream_data(&cfb, "/Folder/Small"), vec![1; 100]);
+	}
+
+	#[test]
+	fn freed_sectors_are_reused_by_the_next_commit_only() {
+		let original = CompoundFile::parse_metadata_from_reader(&mut sample_file()).unwrap();
+		let (_, large_offsets) = original.stream_offsets(&original.open_stream("/Storage/Large").unwrap()).unwrap();
+		let (_, other_offsets) = original.stream_offsets(&original.open_stream("/Other").unwrap()).unwrap();
+
+		let mut editor = CompoundFileEditor::open(sample_file()).unwrap();
+		editor.remove("/Storage/Large").unwrap();
+		editor.replace_stream("/Other", vec![5; 200]).unwrap();
+		editor.create_stream("/New", vec![6; 5000]).unwrap();
+		let cfb = commit_and_parse(editor);
+		let (_, new_offsets) = cfb.stream_offsets(&cfb.open_stream("/New").unwrap()).unwrap();
+		let (_, replaced_offsets) = cfb.stream_offsets(&cfb.open_stream("/Other").unwrap()).unwrap();
+		assert!(new_offsets.iter().all(|offset| !large_offsets.contains(offset)));
+		assert!(replaced_offsets.iter().all(|offset| !other_offsets.contains(offset)));
+		assert_eq!(stream_data(&cfb, "/New"), vec![6; 5000]);
+		assert_eq!(stream_data(&cfb, "/Other"), vec![5; 200]);
+
+		// the old contents are left in place, and the next commit reuses their sectors
+		let mut editor = CompoundFileEditor::open(sample_file()).unwrap();
+		editor.remove("/Storage/Large").unwrap();
+		editor.commit().unwrap();
+		let bytes = editor.file.get_ref();
+		let mut old_data: Vec<u8> = large_offsets.iter().flat_map(|&offset| &bytes[offset as usize..][..512]).copied().collect();
+		old_data.truncate(5000);
+		assert_eq!(old_data, vec![2; 5000]);
+		let mut editor = CompoundFileEditor::open(editor.into_inner()).unwrap();
+		editor.create_stream("/New", vec![6; 5000]).unwrap();
+		let cfb = commit_and_parse(editor);
+		let (_, new_offsets) = cfb.stream_offsets(&cfb.open_stream("/New").unwrap()).unwrap();
+		assert!(new_offsets.iter().any(|offset| large_offsets.contains(offset)));
+	}
+
+	// Records the offset of every write to a file.
+	struct RecordingFile {
+		inner: Cursor<Vec<u8>>,
+		writes: Vec<u64>,
+	}
+
+	impl Read for RecordingFile {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			self.inner.read(buf)
+		}
+	}
+
+	impl Write for RecordingFile {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.writes.push(self.inner.position());
+			self.inner.write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Seek for RecordingFile {
+		fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+			self.inner.seek(pos)
+		}
+	}
+
+	#[test]
+	fn stream_contents_are_written_before_the_directory_and_fat() {
+		let mut editor = CompoundFileEditor::open(RecordingFile { inner: sample_file(), writes: Vec::new() }).unwrap();
+		editor.remove("/Other").unwrap();
+		editor.create_stream("/New", vec![6; 5000]).unwrap();
+		editor.create_stream("/NewSmall", vec![7; 300]).unwrap();
+		editor.commit().unwrap();
+		let RecordingFile { mut inner, writes } = editor.into_inner();
+		inner.set_position(0);
+		let cfb = CompoundFile::parse_from_reader(&mut inner).unwrap();
+		assert_eq!(cfb.check(), Vec::new());
+
+		let sector_size = cfb.header.sector_size() as u64;
+		let mut stream_offsets = Vec::new();
+		for path in ["/New", "/NewSmall"] {
+			stream_offsets.extend(cfb.stream_offsets(&cfb.open_stream(path).unwrap()).unwrap().1);
+		}
+		let table_sectors = [
+			fat::get_chain(&cfb.fat, cfb.header.dir_first_sector).unwrap(),
+			fat::get_chain(&cfb.fat, cfb.header.minifat_first_sector).unwrap(),
+			cfb.difat.clone(),
+		];
+		let table_offsets: Vec<u64> = table_sectors.iter().flatten().map(|&sector| cfb.header.sector_offset(sector) as u64).collect();
+		let is_table_write = |offset: u64| offset == 0 || table_offsets.contains(&(offset - offset % sector_size));
+		let first_table_write = writes.iter().position(|&offset| is_table_write(offset)).unwrap();
+		let last_stream_write = writes.iter().rposition(|offset| stream_offsets.contains(offset)).unwrap();
+		assert!(last_stream_write < first_table_write);
+	}
+}
@@ -1,6 +1,214 @@
 use std::boxed::Box;
 use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::marker::{Send, Sync};
 
+use nom::error::{ErrorKind, ParseError};
+
 pub type BoxError = Box<dyn Error + Send + Sync>;
-pub type BoxResult<T> = Result<T, BoxError>;
\ No newline at end of file
+pub type BoxResult<T> = Result<T, BoxError>;
+
+pub type CfbResult<T> = Result<T, CfbError>;
+pub type MsgResult<T> = Result<T, MsgError>;
+
+#[derive(Debug)]
+pub enum CfbError {
+	Io(std::io::Error),
+	// header could not be parsed, e.g. because of a wrong signature
+	InvalidHeader,
+	UnsupportedSectorShift { sector_shift: u16 },
+	UnsupportedVersion { version_major: u16 },
+	// sector shift is not the one required by the major version
+	SectorShiftMismatch { version_major: u16, sector_shift: u16 },
+	InvalidByteOrder { byte_order: u16 },
+	InvalidMiniSectorShift { mini_sector_shift: u16 },
+	InvalidMiniStreamCutoffSize { size: u32 },
+	NonZeroReserved,
+	DirSectorsInV3 { dir_sectors: u32 },
+	// header specifies more sectors than the file holds
+	SectorCountExceedsFile { sectors: u32, file_sectors: u64 },
+	// sector number is a special value or lies outside of the FAT, MiniFAT or file
+	SectorOutOfRange { sector: u32 },
+	MiniSectorOutOfRange { sector: u32 },
+	// sector chain starting at start visits a sector twice
+	ChainCycle { start: u32 },
+	DifatSectorCount { expected: u32, actual: usize },
+	DifatSectorNotMarked { sector: u32 },
+	FatSectorCount { expected: u32, actual: usize },
+	InvalidFatSector { sector: u32 },
+	InvalidDirectorySector { sector: u32 },
+	// stream size disagrees with the length of its sector chain
+	StreamSizeMismatch { id: u32, size: u64, sectors: usize },
+	StreamTooLarge { name: String },
+	// stream data has not been read, e.g. after a metadata-only parse
+	DataNotLoaded { id: u32 },
+	TooManySectors,
+	MissingRootEntry,
+	InvalidDirectoryId { id: u32 },
+	DirectoryEntryShared { id: u32 },
+	DuplicateName { name: String },
+	NameTooLong { name: String },
+	// creation or modified time of entry lies outside of the FILETIME range
+	TimestampOutOfRange { name: String },
+	InvalidName { name: String },
+	MissingEntry { path: String },
+	NotAStream { path: String },
+	NotAStorage { path: String },
+	// the creation time of the root storage must be zero
+	RootCreationTime,
+	// line of an extracted tree's metadata sidecar could not be parsed
+	InvalidSidecar { path: String, line: usize },
+}
+
+impl Display for CfbError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(err) => write!(f, "I/O error: {}", err),
+			Self::InvalidHeader => write!(f, "invalid compound file header"),
+			Self::UnsupportedSectorShift { sector_shift } => write!(f, "unsupported sector shift {}", sector_shift),
+			Self::UnsupportedVersion { version_major } => write!(f, "unsupported major version {}", version_major),
+			Self::SectorShiftMismatch { version_major, sector_shift } => write!(f, "sector shift {} is invalid for major version {}", sector_shift, version_major),
+			Self::InvalidByteOrder { byte_order } => write!(f, "invalid byte order {:#06X}", byte_order),
+			Self::InvalidMiniSectorShift { mini_sector_shift } => write!(f, "invalid mini sector shift {}", mini_sector_shift),
+			Self::InvalidMiniStreamCutoffSize { size } => write!(f, "invalid mini stream cutoff size {}", size),
+			Self::NonZeroReserved => write!(f, "reserved header field is not zero"),
+			Self::DirSectorsInV3 { dir_sectors } => write!(f, "version 3 header specifies {} directory sectors instead of 0", dir_sectors),
+			Self::SectorCountExceedsFile { sectors, file_sectors } => write!(f, "header specifies {} sectors, but the file holds only {}", sectors, file_sectors),
+			Self::SectorOutOfRange { sector } => write!(f, "sector {:#010X} is out of range", sector),
+			Self::MiniSectorOutOfRange { sector } => write!(f, "mini sector {:#010X} is outside of the mini stream", sector),
+			Self::ChainCycle { start } => write!(f, "sector chain starting at sector {} contains a cycle", start),
+			Self::DifatSectorCount { expected, actual } => write!(f, "DIFAT chain has {} sectors, header specifies {}", actual, expected),
+			Self::DifatSectorNotMarked { sector } => write!(f, "DIFAT sector {} is not marked as DIFSECT in FAT", sector),
+			Self::FatSectorCount { expected, actual } => write!(f, "DIFAT lists {} FAT sectors, header specifies {}", actual, expected),
+			Self::InvalidFatSector { sector } => write!(f, "could not parse FAT sector {}", sector),
+			Self::InvalidDirectorySector { sector } => write!(f, "could not parse directory sector {}", sector),
+			Self::StreamSizeMismatch { id, size, sectors } => write!(f, "stream size {} of directory entry {} does not match number of sectors {}", size, id, sectors),
+			Self::StreamTooLarge { name } => write!(f, "stream {:?} is too large for a version 3 compound file", name),
+			Self::DataNotLoaded { id } => write!(f, "data of stream {} is not loaded", id),
+			Self::TooManySectors => write!(f, "compound file has too many sectors"),
+			Self::MissingRootEntry => write!(f, "compound file has no root directory entry"),
+			Self::InvalidDirectoryId { id } => write!(f, "invalid directory entry ID {}", id),
+			Self::DirectoryEntryShared { id } => write!(f, "directory entry {} is linked more than once", id),
+			Self::DuplicateName { name } => write!(f, "duplicate directory entry name {:?}", name),
+			Self::NameTooLong { name } => write!(f, "directory entry name {:?} is longer than 31 characters", name),
+			Self::TimestampOutOfRange { name } => write!(f, "timestamp of directory entry {:?} cannot be stored as a FILETIME", name),
+			Self::InvalidName { name } => write!(f, "invalid directory entry name {:?}", name),
+			Self::MissingEntry { path } => write!(f, "{:?} not found", path),
+			Self::NotAStream { path } => write!(f, "{:?} is not a stream object", path),
+			Self::NotAStorage { path } => write!(f, "{:?} is not a storage object", path),
+			Self::RootCreationTime => write!(f, "the root storage cannot have a creation time"),
+			Self::InvalidSidecar { path, line } => write!(f, "invalid metadata on line {} of {:?}", line, path),
+		}
+	}
+}
+
+impl Error for CfbError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			Self::Io(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<std::io::Error> for CfbError {
+	fn from(err: std::io::Error) -> Self {
+		Self::Io(err)
+	}
+}
+
+#[derive(Debug)]
+pub enum MsgError {
+	Cfb(CfbError),
+	// stream referenced by a property stream is missing
+	MissingStream { path: String },
+	// path is the stream holding the property value
+	PropertySizeMismatch { tag: u32, path: String, expected: usize, actual: usize },
+	InvalidPropertyValue { tag: u32, path: String },
+	InvalidPropertyStream { path: String },
+	// nom parser failure without further context
+	Parse(ErrorKind),
+}
+
+impl MsgError {
+	// Qualifies an error raised while parsing the property stream of the
+	// storage at storage_path, which only knows stream names, with full paths.
+	pub(crate) fn in_storage(self, storage_path: &str) -> Self {
+		match self {
+			Self::Parse(_) => Self::InvalidPropertyStream { path: format!("{}/{}", storage_path, crate::oxmsg::PROPERTY_STREAM_NAME) },
+			Self::MissingStream { path } => Self::MissingStream { path: format!("{}/{}", storage_path, path) },
+			Self::PropertySizeMismatch { tag, path, expected, actual } => Self::PropertySizeMismatch { tag, path: format!("{}/{}", storage_path, path), expected, actual },
+			Self::InvalidPropertyValue { tag, path } => Self::InvalidPropertyValue { tag, path: format!("{}/{}", storage_path, path) },
+			err => err,
+		}
+	}
+}
+
+impl Display for MsgError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Cfb(err) => Display::fmt(err, f),
+			Self::MissingStream { path } => write!(f, "stream {:?} is missing", path),
+			Self::PropertySizeMismatch { tag, path, expected, actual } => write!(f, "property {:#010X} in {:?} has size {}, expected {}", tag, path, actual, expected),
+			Self::InvalidPropertyValue { tag, path } => write!(f, "could not parse value of property {:#010X} in {:?}", tag, path),
+			Self::InvalidPropertyStream { path } => write!(f, "could not parse property stream {:?}", path),
+			Self::Parse(kind) => write!(f, "parse error: {}", kind.description()),
+		}
+	}
+}
+
+impl Error for MsgError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			Self::Cfb(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<CfbError> for MsgError {
+	fn from(err: CfbError) -> Self {
+		Self::Cfb(err)
+	}
+}
+
+impl<I> From<nom::error::Error<I>> for MsgError {
+	fn from(err: nom::error::Error<I>) -> Self {
+		Self::Parse(err.code)
+	}
+}
+
+impl From<nom::Err<MsgError>> for MsgError {
+	fn from(err: nom::Err<MsgError>) -> Self {
+		match err {
+			nom::Err::Error(err) | nom::Err::Failure(err) => err,
+			nom::Err::Incomplete(_) => Self::Parse(ErrorKind::Eof),
+		}
+	}
+}
+
+impl<I> ParseError<I> for MsgError {
+	fn from_error_kind(_input: I, kind: ErrorKind) -> Self {
+		Self::Parse(kind)
+	}
+
+	fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+		other
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn in_storage_qualifies_paths() {
+		let storage = "/__attach_version1.0_#00000000";
+		let err = MsgError::PropertySizeMismatch { tag: 0x3701_0102, path: "__substg1.0_37010102".to_string(), expected: 4, actual: 2 }.in_storage(storage);
+		assert!(matches!(err, MsgError::PropertySizeMismatch { path, .. } if path == format!("{}/__substg1.0_37010102", storage)));
+		let err = MsgError::InvalidPropertyValue { tag: 0x0E07_0003, path: crate::oxmsg::PROPERTY_STREAM_NAME.to_string() }.in_storage(storage);
+		assert!(matches!(err, MsgError::InvalidPropertyValue { path, .. } if path == format!("{}/{}", storage, crate::oxmsg::PROPERTY_STREAM_NAME)));
+		let err = MsgError::MissingStream { path: "__substg1.0_0037001F".to_string() }.in_storage("");
+		assert!(matches!(err, MsgError::MissingStream { path } if path == "/__substg1.0_0037001F"));
+	}
+}
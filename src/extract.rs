@@ -0,0 +1,264 @@
+use crate::builder::CompoundFileBuilder;
+use crate::cfb::{CompoundFile, CompoundFileHeader};
+use crate::dir::{self, DirectoryEntry, EntryName};
+use crate::error::{CfbError, CfbResult};
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+// File in every extracted directory holding the metadata of the storage and
+// its streams, one tab-separated line per entry: escaped name ("." for the
+// storage itself), CLSID, state bits in hex, and creation and modified time
+// in RFC 3339 or "-" if unset. Streams have no CLSID or times in [MS-CFB], so
+// theirs are always written as zero and "-". Escaped names only contain '%'
+// followed by two hex digits, so no entry can clash with it.
+pub const SIDECAR_NAME: &str = "%meta";
+
+fn must_escape(c: char) -> bool {
+	c.is_ascii_control() || matches!(c, '%' | '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|')
+}
+
+// Escapes characters that are not allowed or not portable in file names as
+// %XX, e.g. "\x05SummaryInformation" becomes "%05SummaryInformation".
+// Trailing dots and spaces are escaped as well since Windows strips them,
+// which also covers the names "." and "..".
+pub fn escape_name(name: &str) -> String {
+	let keep = name.trim_end_matches(['.', ' ']).len();
+	let mut escaped = String::with_capacity(name.len());
+	for (i, c) in name.char_indices() {
+		if i >= keep || must_escape(c) {
+			let _ = write!(escaped, "%{:02X}", c as u32);
+		} else {
+			escaped.push(c);
+		}
+	}
+	escaped
+}
+
+// Reverses escape_name. Only ASCII characters are escaped, so escapes of
+// bytes of 0x80 and above are rejected rather than read as Latin-1.
+pub fn unescape_name(escaped: &str) -> CfbResult<String> {
+	let mut name = String::with_capacity(escaped.len());
+	let mut chars = escaped.chars();
+	while let Some(c) = chars.next() {
+		if c != '%' {
+			name.push(c);
+			continue;
+		}
+		let hex: String = chars.by_ref().take(2).collect();
+		if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+			return Err(CfbError::InvalidName { name: escaped.to_string() });
+		}
+		match u8::from_str_radix(&hex, 16) {
+			Ok(byte) if byte.is_ascii() => name.push(byte as char),
+			_ => return Err(CfbError::InvalidName { name: escaped.to_string() }),
+		}
+	}
+	Ok(name)
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+	time.map_or_else(|| "-".to_string(), |time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+fn parse_time(s: &str) -> Option<Option<DateTime<Utc>>> {
+	if s == "-" {
+		return Some(None);
+	}
+	DateTime::parse_from_rfc3339(s).ok().map(|time| Some(time.with_timezone(&Utc)))
+}
+
+fn sidecar_line(name: &str, entry: &DirectoryEntry) -> String {
+	let storage = entry.object_type != dir::OBJECT_STREAM;
+	format!(
+		"{}\t{}\t{:08X}\t{}\t{}\n",
+		name,
+		dir::format_clsid(if storage { &entry.clsid } else { &[0; 16] }),
+		entry.state_bits,
+		// the root storage has no creation time either
+		format_time(entry.creation_time.filter(|_| storage && entry.object_type != dir::OBJECT_ROOT_STORAGE)),
+		format_time(entry.modified_time.filter(|_| storage)),
+	)
+}
+
+fn extract_storage(storage: &DirectoryEntry, path: &Path) -> CfbResult<()> {
+	fs::create_dir_all(path)?;
+	let mut sidecar = sidecar_line(".", storage);
+	for (name, child) in storage.children.borrow().iter() {
+		let escaped = escape_name(name.as_str());
+		if escaped.is_empty() {
+			return Err(CfbError::InvalidName { name: escaped });
+		}
+		match child.object_type {
+			dir::OBJECT_STORAGE => extract_storage(child, &path.join(&escaped))?,
+			dir::OBJECT_STREAM => {
+				fs::write(path.join(&escaped), child.data.borrow().as_slice())?;
+				sidecar.push_str(&sidecar_line(&escaped, child));
+			}
+			_ => {}
+		}
+	}
+	fs::write(path.join(SIDECAR_NAME), sidecar)?;
+	Ok(())
+}
+
+// Writes the tree of cfb below path, creating a directory for every storage
+// and a file for every stream, named with escape_name, and a SIDECAR_NAME
+// file in every directory. Stream data is taken from the parsed entries, so
+// nothing is written unless cfb was parsed with its data.
+pub fn extract(cfb: &CompoundFile, path: &Path) -> CfbResult<()> {
+	let root = cfb.dirs.first().ok_or(CfbError::MissingRootEntry)?;
+	cfb.check_data_loaded()?;
+	extract_storage(root, path)
+}
+
+fn pack_storage(builder: &mut CompoundFileBuilder, path: &Path, entry_path: &str) -> CfbResult<()> {
+	let mut file_names = fs::read_dir(path)?
+		.map(|dir_entry| dir_entry.map(|dir_entry| dir_entry.file_name()))
+		.collect::<io::Result<Vec<_>>>()?;
+	file_names.sort();
+
+	let mut streams = HashSet::new();
+	for file_name in file_names {
+		if file_name == SIDECAR_NAME {
+			continue;
+		}
+		let escaped = file_name.to_str().ok_or_else(|| CfbError::InvalidName { name: file_name.to_string_lossy().into_owned() })?;
+		let name = unescape_name(escaped)?;
+		let child_path = format!("{}/{}", entry_path, name);
+		let file_path = path.join(&file_name);
+		if fs::metadata(&file_path)?.is_dir() {
+			builder.create_storage(&child_path)?;
+			pack_storage(builder, &file_path, &child_path)?;
+		} else {
+			builder.create_stream(&child_path, fs::read(&file_path)?)?;
+			streams.insert(EntryName::from(name));
+		}
+	}
+
+	let sidecar_path = path.join(SIDECAR_NAME);
+	let sidecar = match fs::read_to_string(&sidecar_path) {
+		Ok(sidecar) => sidecar,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err.into()),
+	};
+	for (i, line) in sidecar.lines().enumerate() {
+		if line.is_empty() {
+			continue;
+		}
+		let invalid = || CfbError::InvalidSidecar { path: sidecar_path.display().to_string(), line: i + 1 };
+		let fields: Vec<&str> = line.split('\t').collect();
+		let [name, clsid, state_bits, creation_time, modified_time] = fields[..] else {
+			return Err(invalid());
+		};
+		let clsid = dir::parse_clsid(clsid).ok_or_else(invalid)?;
+		let state_bits = u32::from_str_radix(state_bits, 16).map_err(|_| invalid())?;
+		let creation_time = parse_time(creation_time).ok_or_else(invalid)?;
+		let modified_time = parse_time(modified_time).ok_or_else(invalid)?;
+		if name == "." {
+			builder.set_clsid(entry_path, clsid)?
+				.set_state_bits(entry_path, state_bits)?
+				.set_creation_time(entry_path, creation_time)?
+				.set_modified_time(entry_path, modified_time)?;
+			continue;
+		}
+		// lines of streams deleted by hand are ignored
+		let name = unescape_name(name)?;
+		if !streams.contains(&EntryName::from(name.as_str())) {
+			continue;
+		}
+		if clsid != [0; 16] || creation_time.is_some() || modified_time.is_some() {
+			return Err(invalid());
+		}
+		builder.set_state_bits(&format!("{}/{}", entry_path, name), state_bits)?;
+	}
+	Ok(())
+}
+
+// Packs a directory written by extract, or created by hand, back into a
+// compound file with the given header. Directories become storages and files
+// become streams, with names unescaped by unescape_name; metadata missing
+// from the sidecar files is left at its default.
+pub fn pack(path: &Path, header: CompoundFileHeader) -> CfbResult<CompoundFile> {
+	let mut builder = CompoundFileBuilder::new(header);
+	pack_storage(&mut builder, path, "")?;
+	builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::walk::WalkOrder;
+
+	use std::io::Cursor;
+	use std::path::PathBuf;
+
+	use chrono::{Duration, TimeZone};
+
+	fn temp_path(name: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("nomcfb-extract-{}-{}", std::process::id(), name));
+		let _ = fs::remove_dir_all(&path);
+		path
+	}
+
+	fn sample() -> CompoundFile {
+		let time = Utc.with_ymd_and_hms(2020, 2, 29, 12, 30, 0).unwrap() + Duration::nanoseconds(123_456_700);
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap()
+			.create_stream("/Storage/\u{5}Summary", b"summary".to_vec()).unwrap()
+			.create_stream("/Trailing.", vec![1; 5000]).unwrap()
+			.create_stream("/Empty", Vec::new()).unwrap()
+			.set_clsid("/Storage", [7; 16]).unwrap()
+			.set_state_bits("/Trailing.", 0x1234).unwrap()
+			.set_creation_time("/Storage", Some(time)).unwrap()
+			.set_modified_time("/Storage", Some(time)).unwrap()
+			.set_modified_time("", Some(time)).unwrap();
+		builder.build().unwrap()
+	}
+
+	// Every entry's path and everything extract keeps of it.
+	type Summary = Vec<(String, u8, [u8; 16], u32, Option<DateTime<Utc>>, Option<DateTime<Utc>>, Vec<u8>)>;
+
+	fn summary(cfb: &CompoundFile) -> Summary {
+		cfb.walk(WalkOrder::PreOrder)
+			.map(|(path, _, entry)| (path, entry.object_type, entry.clsid, entry.state_bits, entry.creation_time, entry.modified_time, entry.data.borrow().clone()))
+			.collect()
+	}
+
+	#[test]
+	fn extract_and_pack_round_trip() {
+		let cfb = sample();
+		let path = temp_path("round-trip");
+		cfb.extract(&path).unwrap();
+		assert_eq!(fs::read(path.join("Storage").join("%05Summary")).unwrap(), b"summary");
+		assert!(path.join("Trailing%2E").is_file());
+
+		let packed = CompoundFile::pack(&path, CompoundFileHeader::new_v3()).unwrap();
+		assert_eq!(summary(&packed), summary(&cfb));
+		fs::remove_dir_all(path).unwrap();
+	}
+
+	#[test]
+	fn extract_requires_data() {
+		let mut bytes = Cursor::new(Vec::new());
+		sample().write_to(&mut bytes).unwrap();
+		bytes.set_position(0);
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut bytes).unwrap();
+		let path = temp_path("metadata-only");
+		assert!(matches!(cfb.extract(&path), Err(CfbError::DataNotLoaded { .. })));
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn unescape_rejects_non_ascii_escapes() {
+		assert_eq!(unescape_name("%05Summary%2E").unwrap(), "\u{5}Summary.");
+		assert!(unescape_name("%C3%A9").is_err());
+		assert!(unescape_name("%5").is_err());
+	}
+}
@@ -1,3 +1,5 @@
+use crate::error::{CfbError, CfbResult};
+
 use nom::{
 	IResult,
 	number::streaming::le_u32,
@@ -14,6 +16,90 @@ pub const FATSECT: u32      = 0xFFFFFFFD; // Specifies a FAT sector in the FAT
 pub const ENDOFCHAIN: u32   = 0xFFFFFFFE; // End of a linked chain of sectors.
 pub const FREESECT: u32     = 0xFFFFFFFF; // Specifies an unallocated sector in the FAT, Mini FAT, or DIFAT.
 
+// Returns the sector numbers of the chain starting at sector.
+pub fn get_chain(entries: &[u32], start: u32) -> CfbResult<Vec<u32>> {
+	let mut chain = Vec::new();
+	let mut sector = start;
+	while sector != ENDOFCHAIN {
+		if sector > MAXREGSECT {
+			return Err(CfbError::SectorOutOfRange { sector });
+		}
+		// a chain with more sectors than the table has entries must visit a sector twice
+		if chain.len() >= entries.len() {
+			return Err(CfbError::ChainCycle { start });
+		}
+		chain.push(sector);
+		sector = *entries.get(sector as usize).ok_or(CfbError::SectorOutOfRange { sector })?;
+	}
+	Ok(chain)
+}
+
+// Follows a chain one sector at a time, failing like get_chain on invalid
+// links and cycles. Visited sectors are kept as one bit per table entry.
+pub(crate) struct ChainIter<'a> {
+	entries: &'a [u32],
+	start: u32,
+	sector: u32,
+	visited: Vec<u64>,
+}
+
+impl<'a> ChainIter<'a> {
+	pub(crate) fn new(entries: &'a [u32], start: u32) -> Self {
+		Self { entries, start, sector: start, visited: vec![0; entries.len().div_ceil(64)] }
+	}
+}
+
+impl Iterator for ChainIter<'_> {
+	type Item = CfbResult<u32>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let sector = self.sector;
+		if sector == ENDOFCHAIN {
+			return None;
+		}
+		// stop after an error
+		self.sector = ENDOFCHAIN;
+		let next = match self.entries.get(sector as usize) {
+			Some(&next) if sector <= MAXREGSECT => next,
+			_ => return Some(Err(CfbError::SectorOutOfRange { sector })),
+		};
+		let (word, bit) = (sector as usize / 64, 1 << (sector % 64));
+		if self.visited[word] & bit != 0 {
+			return Some(Err(CfbError::ChainCycle { start: self.start }));
+		}
+		self.visited[word] |= bit;
+		self.sector = next;
+		Some(Ok(sector))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainEnd {
+	EndOfChain,
+	// chain links to a special value other than ENDOFCHAIN or past the end of the table
+	InvalidSector(u32),
+	// chain links back to a sector it already visited
+	Cycle(u32),
+}
+
+// Returns the sector numbers of the chain starting at sector up to the first
+// invalid link or repeated sector, along with how the chain ended.
+pub fn get_partial_chain(entries: &[u32], start: u32) -> (Vec<u32>, ChainEnd) {
+	let mut chain = Vec::new();
+	let mut visited = vec![false; entries.len()];
+	let mut sector = start;
+	while sector != ENDOFCHAIN {
+		match visited.get_mut(sector as usize) {
+			Some(true) => return (chain, ChainEnd::Cycle(sector)),
+			Some(seen) => *seen = true,
+			None => return (chain, ChainEnd::InvalidSector(sector)),
+		}
+		chain.push(sector);
+		sector = entries[sector as usize];
+	}
+	(chain, ChainEnd::EndOfChain)
+}
+
 pub trait Fat<'a> {
 	fn parse(input: &[u8]) -> IResult<&[u8], Self> where Self: Sized;
 	fn entries(&'a self) -> &'a [u32];
@@ -0,0 +1,342 @@
+use crate::cfb::{CompoundFile, HEADER_SIZE};
+use crate::dir::{self, DirectoryEntry};
+use crate::error::{CfbError, CfbResult};
+use crate::fat;
+use crate::recover::{read_fat_stream, read_sector};
+
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+// Bytes that are part of the file but not of any structure or stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slack {
+	pub offset: u64, // file offset of the first byte
+	pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeSector {
+	Sector(u32),
+	MiniSector(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebuildMethod {
+	// the stale FAT or MiniFAT chain from the starting sector was still intact
+	StaleChain,
+	// the chain was freed, so the sectors are assumed to follow the starting sector
+	Contiguous,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuiltStream {
+	pub data: Vec<u8>,
+	pub method: RebuildMethod,
+	// sector or mini sector numbers the data was read from
+	pub sectors: Vec<u32>,
+	// at least one of the sectors now belongs to a live chain
+	pub overwritten: bool,
+}
+
+// Marks every sector and mini sector that belongs to a live structure or to
+// an allocated directory entry.
+fn live_sectors(cfb: &CompoundFile) -> (Vec<bool>, Vec<bool>) {
+	let mut live = vec![false; cfb.fat.len()];
+	let mut mini_live = vec![false; cfb.minifat.len()];
+	let mut chains = vec![cfb.header.dir_first_sector, cfb.header.minifat_first_sector];
+	for sector in cfb.difat.iter().chain(&cfb.difat_sectors) {
+		if let Some(live) = live.get_mut(*sector as usize) {
+			*live = true;
+		}
+	}
+	for (id, entry) in cfb.dirs.iter().enumerate() {
+		if id == 0 || (entry.object_type == dir::OBJECT_STREAM && entry.stream_size >= cfb.header.mini_stream_cutoff_size as u64) {
+			chains.push(entry.starting_sector);
+		} else if entry.object_type == dir::OBJECT_STREAM {
+			for sector in fat::get_partial_chain(&cfb.minifat, entry.starting_sector).0 {
+				mini_live[sector as usize] = true;
+			}
+		}
+	}
+	for start in chains {
+		for sector in fat::get_partial_chain(&cfb.fat, start).0 {
+			live[sector as usize] = true;
+		}
+	}
+	(live, mini_live)
+}
+
+// Returns the unallocated directory entries that still hold a name, which
+// usually belong to deleted storages or streams.
+pub fn deleted_entries(cfb: &CompoundFile) -> Vec<(u32, Rc<DirectoryEntry>)> {
+	cfb.dirs.iter().enumerate()
+		.filter(|(_, entry)| entry.object_type == dir::OBJECT_UNKNOWN && !entry.name.is_empty())
+		.map(|(id, entry)| (id as u32, entry.clone()))
+		.collect()
+}
+
+// Returns the sectors marked FREESECT in the FAT, and the mini sectors marked
+// FREESECT in the MiniFAT, that still hold non-zero bytes.
+pub fn free_sectors_with_data<R: Read + Seek>(cfb: &CompoundFile, reader: &mut R) -> CfbResult<Vec<FreeSector>> {
+	let header = &cfb.header;
+	let mut buf = Vec::with_capacity(header.sector_size());
+	let mut sectors = Vec::new();
+	let file_size = reader.seek(SeekFrom::End(0))?;
+	for (sector, &value) in cfb.fat.iter().enumerate() {
+		if value != fat::FREESECT || header.sector_offset(sector as u32) as u64 >= file_size {
+			continue;
+		}
+		let read = read_sector(reader, &mut buf, header, sector as u32);
+		if buf[..read].iter().any(|&b| b != 0) {
+			sectors.push(FreeSector::Sector(sector as u32));
+		}
+	}
+	let root = cfb.dirs.first().ok_or(CfbError::MissingRootEntry)?;
+	let mini_stream_bytes = read_fat_stream(reader, &mut buf, header, &cfb.fat, root.starting_sector, root.stream_size as usize);
+	for (sector, chunk) in mini_stream_bytes.chunks(fat::MINIFAT_SECTOR_SIZE).enumerate() {
+		if cfb.minifat.get(sector).map_or(true, |&value| value == fat::FREESECT) && chunk.iter().any(|&b| b != 0) {
+			sectors.push(FreeSector::MiniSector(sector as u32));
+		}
+	}
+	Ok(sectors)
+}
+
+// Rebuilds the data of a deleted stream from its directory entry. If the
+// FAT or MiniFAT still holds a long enough chain from the starting sector it
+// is followed, otherwise the stream is assumed to be stored contiguously.
+pub fn rebuild_deleted_stream<R: Read + Seek>(cfb: &CompoundFile, reader: &mut R, id: u32) -> CfbResult<RebuiltStream> {
+	let header = &cfb.header;
+	let entry = cfb.dirs.get(id as usize).ok_or(CfbError::InvalidDirectoryId { id })?;
+	let size = entry.stream_size as usize;
+	let mini = entry.stream_size < header.mini_stream_cutoff_size as u64;
+	let (entries, chunk_size) = if mini { (&cfb.minifat, fat::MINIFAT_SECTOR_SIZE) } else { (&cfb.fat, header.sector_size()) };
+	let needed = size.div_ceil(chunk_size);
+
+	let (mut sectors, _) = fat::get_partial_chain(entries, entry.starting_sector);
+	sectors.truncate(needed);
+	let method = if sectors.len() == needed {
+		RebuildMethod::StaleChain
+	} else {
+		let start = (entry.starting_sector as usize).min(entries.len());
+		let end = start.saturating_add(needed).min(entries.len());
+		sectors = (start as u32..end as u32).collect();
+		RebuildMethod::Contiguous
+	};
+
+	let (live, mini_live) = live_sectors(cfb);
+	let overwritten = sectors.iter().any(|&sector| if mini { mini_live[sector as usize] } else { live[sector as usize] });
+
+	let mut buf = Vec::with_capacity(header.sector_size());
+	let mut data = Vec::with_capacity(sectors.len() * chunk_size);
+	if mini {
+		let root = cfb.dirs.first().ok_or(CfbError::MissingRootEntry)?;
+		let mini_stream_bytes = read_fat_stream(reader, &mut buf, header, &cfb.fat, root.starting_sector, root.stream_size as usize);
+		for &sector in &sectors {
+			let start = sector as usize * fat::MINIFAT_SECTOR_SIZE;
+			let chunk = mini_stream_bytes.get(start..).unwrap_or_default();
+			let chunk = &chunk[..chunk.len().min(fat::MINIFAT_SECTOR_SIZE)];
+			data.extend_from_slice(chunk);
+			if chunk.len() < fat::MINIFAT_SECTOR_SIZE {
+				break;
+			}
+		}
+	} else {
+		for &sector in &sectors {
+			let read = read_sector(reader, &mut buf, header, sector);
+			data.extend_from_slice(&buf[..read]);
+			if read < header.sector_size() {
+				break;
+			}
+		}
+	}
+	data.truncate(size);
+	Ok(RebuiltStream { data, method, sectors, overwritten })
+}
+
+// Returns the padding between the 512-byte header and the end of the first
+// sector, which only exists in version 4 files.
+pub fn header_slack<R: Read + Seek>(cfb: &CompoundFile, reader: &mut R) -> CfbResult<Slack> {
+	let mut data = Vec::new();
+	reader.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+	reader.take(cfb.header.sector_size().saturating_sub(HEADER_SIZE) as u64).read_to_end(&mut data)?;
+	Ok(Slack { offset: HEADER_SIZE as u64, data })
+}
+
+// Returns the unused parts of the directory sectors: the name field after the
+// name of every allocated entry, and the whole of every unallocated entry.
+pub fn directory_slack<R: Read + Seek>(cfb: &CompoundFile, reader: &mut R) -> CfbResult<Vec<Slack>> {
+	let header = &cfb.header;
+	let mut buf = Vec::with_capacity(header.sector_size());
+	let mut slack = Vec::new();
+	let mut id = 0;
+	for sector in fat::get_chain(&cfb.fat, header.dir_first_sector)? {
+		let read = read_sector(reader, &mut buf, header, sector);
+		let offset = header.sector_offset(sector) as u64;
+		for (i, entry_bytes) in buf[..read].chunks_exact(dir::ENTRY_SIZE).enumerate() {
+			let entry_offset = offset + (i * dir::ENTRY_SIZE) as u64;
+			let start = match cfb.dirs.get(id) {
+				Some(entry) if entry.object_type != dir::OBJECT_UNKNOWN => ((entry.name.encode_utf16().count() + 1) * 2).min(64),
+				_ => 0,
+			};
+			let end = if start == 0 { dir::ENTRY_SIZE } else { 64 };
+			if start < end {
+				slack.push(Slack { offset: entry_offset + start as u64, data: entry_bytes[start..end].to_vec() });
+			}
+			id += 1;
+		}
+	}
+	Ok(slack)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::CompoundFileBuilder;
+	use crate::edit::CompoundFileEditor;
+
+	use std::io::Cursor;
+
+	fn sample() -> Vec<u8> {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap()
+			.create_stream("/Storage/Small", vec![1; 100]).unwrap()
+			.create_stream("/Storage/Large", vec![2; 5000]).unwrap()
+			.create_stream("/Other", vec![3; 300]).unwrap();
+		let mut cursor = Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	fn parse(bytes: &[u8]) -> CompoundFile {
+		CompoundFile::parse_from_reader(&mut Cursor::new(bytes)).unwrap()
+	}
+
+	fn edit(bytes: Vec<u8>, change: impl FnOnce(&mut CompoundFileEditor<Cursor<Vec<u8>>>)) -> Vec<u8> {
+		let mut editor = CompoundFileEditor::open(Cursor::new(bytes)).unwrap();
+		change(&mut editor);
+		editor.commit().unwrap();
+		editor.into_inner().into_inner()
+	}
+
+	fn entry_offset(cfb: &CompoundFile, id: usize) -> usize {
+		let per_sector = cfb.header.sector_size() / dir::ENTRY_SIZE;
+		let chain = fat::get_chain(&cfb.fat, cfb.header.dir_first_sector).unwrap();
+		cfb.header.sector_offset(chain[id / per_sector]) + (id % per_sector) * dir::ENTRY_SIZE
+	}
+
+	// The editor clears the entries of removed streams, so this writes what a
+	// producer that only unlinks them leaves behind into an unused entry of
+	// bytes, and returns its ID.
+	fn plant_deleted_entry(bytes: &mut [u8], entry: &DirectoryEntry) -> u32 {
+		let cfb = parse(bytes);
+		let id = cfb.dirs.iter().position(|entry| entry.object_type == dir::OBJECT_UNKNOWN && entry.name.is_empty()).unwrap();
+		let deleted = DirectoryEntry {
+			object_type: dir::OBJECT_UNKNOWN,
+			left_sibling_id: dir::NOSTREAM,
+			right_sibling_id: dir::NOSTREAM,
+			child_id: dir::NOSTREAM,
+			..DirectoryEntry::clone(entry)
+		};
+		let mut entry_bytes = Vec::new();
+		deleted.write_to(&mut entry_bytes).unwrap();
+		let offset = entry_offset(&cfb, id);
+		bytes[offset..offset + dir::ENTRY_SIZE].copy_from_slice(&entry_bytes);
+		id as u32
+	}
+
+	#[test]
+	fn removed_streams_are_found_and_rebuilt() {
+		let original = sample();
+		let cfb = parse(&original);
+		let large = cfb.open_stream("/Storage/Large").unwrap();
+		let other = cfb.open_stream("/Other").unwrap();
+		let mut bytes = edit(original.clone(), |editor| {
+			editor.remove("/Storage/Large").unwrap()
+				.remove("/Other").unwrap();
+		});
+		let large_id = plant_deleted_entry(&mut bytes, &large);
+		let other_id = plant_deleted_entry(&mut bytes, &other);
+		let cfb = parse(&bytes);
+
+		let deleted: Vec<(u32, String)> = deleted_entries(&cfb).into_iter().map(|(id, entry)| (id, entry.name.clone())).collect();
+		assert_eq!(deleted, [(large_id, "Large".to_string()), (other_id, "Other".to_string())]);
+
+		let free = free_sectors_with_data(&cfb, &mut Cursor::new(&bytes)).unwrap();
+		for sector in fat::get_chain(&parse(&original).fat, large.starting_sector).unwrap() {
+			assert!(free.contains(&FreeSector::Sector(sector)));
+		}
+		for sector in fat::get_chain(&parse(&original).minifat, other.starting_sector).unwrap() {
+			assert!(free.contains(&FreeSector::MiniSector(sector)));
+		}
+
+		// the editor freed the chains, but the builder wrote the streams contiguously
+		for (id, data) in [(large_id, vec![2; 5000]), (other_id, vec![3; 300])] {
+			let rebuilt = rebuild_deleted_stream(&cfb, &mut Cursor::new(&bytes), id).unwrap();
+			assert_eq!((rebuilt.data, rebuilt.method, rebuilt.overwritten), (data, RebuildMethod::Contiguous, false));
+		}
+	}
+
+	#[test]
+	fn stale_chains_are_followed() {
+		let mut bytes = sample();
+		let large = parse(&bytes).open_stream("/Storage/Large").unwrap();
+		let chain = fat::get_chain(&parse(&bytes).fat, large.starting_sector).unwrap();
+		let id = plant_deleted_entry(&mut bytes, &large);
+		let rebuilt = rebuild_deleted_stream(&parse(&bytes), &mut Cursor::new(&bytes), id).unwrap();
+		// the sectors still belong to the live stream the entry was copied from
+		assert_eq!((rebuilt.data, rebuilt.method, rebuilt.sectors, rebuilt.overwritten), (vec![2; 5000], RebuildMethod::StaleChain, chain, true));
+	}
+
+	#[test]
+	fn reused_sectors_are_reported_as_overwritten() {
+		let original = sample();
+		let large = parse(&original).open_stream("/Storage/Large").unwrap();
+		let bytes = edit(original, |editor| {
+			editor.remove("/Storage/Large").unwrap();
+		});
+		let mut bytes = edit(bytes, |editor| {
+			editor.create_stream("/New", vec![4; 5000]).unwrap();
+		});
+		let id = plant_deleted_entry(&mut bytes, &large);
+		let rebuilt = rebuild_deleted_stream(&parse(&bytes), &mut Cursor::new(&bytes), id).unwrap();
+		assert!(rebuilt.overwritten);
+		assert_ne!(rebuilt.data, vec![2; 5000]);
+	}
+
+	#[test]
+	fn header_slack_is_the_rest_of_the_first_sector() {
+		let mut builder = CompoundFileBuilder::new_v4();
+		builder.create_stream("/Stream", vec![1; 10]).unwrap();
+		let mut cursor = Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		let mut bytes = cursor.into_inner();
+		let hidden: Vec<u8> = (0..4096 - HEADER_SIZE).map(|i| i as u8).collect();
+		bytes[HEADER_SIZE..4096].copy_from_slice(&hidden);
+
+		let slack = header_slack(&parse(&bytes), &mut Cursor::new(&bytes)).unwrap();
+		assert_eq!(slack, Slack { offset: HEADER_SIZE as u64, data: hidden });
+		let v3 = sample();
+		assert!(header_slack(&parse(&v3), &mut Cursor::new(&v3)).unwrap().data.is_empty());
+	}
+
+	#[test]
+	fn directory_slack_covers_name_padding_and_unused_entries() {
+		let mut bytes = sample();
+		let cfb = parse(&bytes);
+		let storage_id = cfb.dirs.iter().position(|entry| entry.name == "Storage").unwrap();
+		let unused_id = cfb.dirs.iter().position(|entry| entry.object_type == dir::OBJECT_UNKNOWN).unwrap();
+		// "Storage" and its terminator take 16 of the 64 bytes of the name field
+		let name_padding = entry_offset(&cfb, storage_id) + 16;
+		bytes[name_padding..name_padding + 48].fill(0xAA);
+		let unused = entry_offset(&cfb, unused_id);
+		bytes[unused..unused + 64].fill(0xBB);
+
+		let slack = directory_slack(&parse(&bytes), &mut Cursor::new(&bytes)).unwrap();
+		assert!(slack.contains(&Slack { offset: name_padding as u64, data: vec![0xAA; 48] }));
+		let unused_slack = slack.iter().find(|slack| slack.offset == unused as u64).unwrap();
+		assert_eq!(unused_slack.data.len(), dir::ENTRY_SIZE);
+		assert_eq!(unused_slack.data[..64], [0xBB; 64]);
+		// every entry is accounted for
+		assert_eq!(slack.len(), cfb.dirs.len());
+	}
+}
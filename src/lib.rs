@@ -1,7 +1,23 @@
 pub mod error;
 pub mod cfb;
+pub mod stream;
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod borrowed;
+pub mod shared;
 pub mod fat;
 pub mod dir;
+pub mod walk;
+#[cfg(feature = "serde")]
+pub mod manifest;
+pub mod builder;
+pub mod edit;
+pub mod compact;
+pub mod convert;
+pub mod extract;
+pub mod check;
+pub mod recover;
+pub mod forensic;
 pub mod oxmsg;
 pub mod oxnspi;
 pub mod oxcmsg;
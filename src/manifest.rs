@@ -0,0 +1,211 @@
+use crate::cfb::{CompoundFile, CompoundFileHeader};
+use crate::dir::{self, DirectoryEntry};
+use crate::error::CfbResult;
+use crate::fat;
+use crate::walk::WalkOrder;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Serializes the 109 DIFAT entries of the header as a sequence, since serde
+// only implements its traits for arrays of up to 32 elements.
+pub(crate) mod difat {
+	use serde::{Deserialize, Deserializer, Serialize, Serializer};
+	use serde::de::Error;
+
+	pub fn serialize<S: Serializer>(difat: &[u32; 109], serializer: S) -> Result<S::Ok, S::Error> {
+		difat.as_slice().serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u32; 109], D::Error> {
+		let difat = Vec::<u32>::deserialize(deserializer)?;
+		let len = difat.len();
+		difat.try_into().map_err(|_| D::Error::invalid_length(len, &"109 DIFAT entries"))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectType {
+	Unknown,
+	Storage,
+	Stream,
+	RootStorage,
+	// object type not defined by [MS-CFB]
+	Invalid(u8),
+}
+
+impl From<u8> for ObjectType {
+	fn from(object_type: u8) -> Self {
+		match object_type {
+			dir::OBJECT_UNKNOWN => Self::Unknown,
+			dir::OBJECT_STORAGE => Self::Storage,
+			dir::OBJECT_STREAM => Self::Stream,
+			dir::OBJECT_ROOT_STORAGE => Self::RootStorage,
+			object_type => Self::Invalid(object_type),
+		}
+	}
+}
+
+// How stream data is included in a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DataEncoding {
+	#[default]
+	Omit,
+	Base64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+	pub id: u32,
+	pub path: String,
+	pub object_type: ObjectType,
+	pub clsid: String,
+	pub state_bits: u32,
+	pub creation_time: Option<DateTime<Utc>>,
+	pub modified_time: Option<DateTime<Utc>>,
+	pub size: u64,
+	// sectors are mini sectors in the mini stream rather than sectors of the file
+	pub mini: bool,
+	pub sectors: Vec<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub data: Option<String>,
+}
+
+// Structural summary of a compound file: its header, and every entry of the
+// directory tree in pre-order with its metadata and sector chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+	pub header: CompoundFileHeader,
+	pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+	// Stream data is only available for files parsed with their data, so
+	// DataEncoding::Base64 fails with DataNotLoaded for metadata-only parses.
+	pub fn new(cfb: &CompoundFile, encoding: DataEncoding) -> CfbResult<Self> {
+		if encoding == DataEncoding::Base64 {
+			cfb.check_data_loaded()?;
+		}
+		let ids: HashMap<*const DirectoryEntry, u32> = cfb.dirs.iter()
+			.enumerate()
+			.map(|(id, entry)| (Rc::as_ptr(entry), id as u32))
+			.collect();
+		let cutoff = cfb.header.mini_stream_cutoff_size as u64;
+		let mut entries = Vec::new();
+		for (path, _, entry) in cfb.walk(WalkOrder::PreOrder) {
+			let is_stream = entry.object_type == dir::OBJECT_STREAM;
+			let mini = is_stream && entry.stream_size < cutoff;
+			let sectors = if is_stream && entry.stream_size == 0 {
+				Vec::new()
+			} else if mini {
+				fat::get_chain(&cfb.minifat, entry.starting_sector)?
+			} else if is_stream || entry.object_type == dir::OBJECT_ROOT_STORAGE {
+				fat::get_chain(&cfb.fat, entry.starting_sector)?
+			} else {
+				Vec::new()
+			};
+			let data = match encoding {
+				DataEncoding::Base64 if is_stream => Some(BASE64.encode(entry.data.borrow().as_slice())),
+				_ => None,
+			};
+			entries.push(ManifestEntry {
+				id: ids.get(&(entry as *const DirectoryEntry)).copied().unwrap_or(dir::NOSTREAM),
+				path,
+				object_type: entry.object_type.into(),
+				clsid: dir::format_clsid(&entry.clsid),
+				state_bits: entry.state_bits,
+				creation_time: entry.creation_time,
+				modified_time: entry.modified_time,
+				size: entry.stream_size,
+				mini,
+				sectors,
+				data,
+			});
+		}
+		Ok(Self { header: cfb.header, entries })
+	}
+
+	pub fn to_json(&self) -> serde_json::Result<String> {
+		serde_json::to_string_pretty(self)
+	}
+}
+
+impl CompoundFile {
+	pub fn manifest(&self, encoding: DataEncoding) -> CfbResult<Manifest> {
+		Manifest::new(self, encoding)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::CompoundFileBuilder;
+	use crate::error::CfbError;
+
+	use chrono::TimeZone;
+	use std::io::Cursor;
+
+	fn sample() -> Vec<u8> {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/Storage").unwrap()
+			.create_stream("/Storage/Small", vec![1; 100]).unwrap()
+			.create_stream("/Large", vec![2; 5000]).unwrap()
+			.set_clsid("/Storage", [3; 16]).unwrap()
+			.set_modified_time("/Storage", Some(Utc.timestamp_opt(1_700_000_000, 123_456_700).unwrap())).unwrap();
+		let mut cursor = Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		cursor.into_inner()
+	}
+
+	fn entry<'a>(manifest: &'a Manifest, path: &str) -> &'a ManifestEntry {
+		manifest.entries.iter().find(|entry| entry.path == path).unwrap()
+	}
+
+	#[test]
+	fn omit_lists_metadata_and_chains_only() {
+		let bytes = sample();
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		let manifest = cfb.manifest(DataEncoding::Omit).unwrap();
+		assert_eq!(manifest.header, cfb.header);
+		assert_eq!(manifest.entries.len(), 4);
+		assert_eq!(manifest.entries[0].object_type, ObjectType::RootStorage);
+		assert!(manifest.entries.iter().all(|entry| entry.data.is_none()));
+
+		let storage = entry(&manifest, "/Storage");
+		assert_eq!((storage.object_type, storage.clsid.as_str()), (ObjectType::Storage, dir::format_clsid(&[3; 16]).as_str()));
+		assert!(storage.sectors.is_empty());
+		let small = entry(&manifest, "/Storage/Small");
+		assert_eq!((small.size, small.mini, small.sectors.len()), (100, true, 2));
+		let large = entry(&manifest, "/Large");
+		assert_eq!((large.size, large.mini, large.sectors.len()), (5000, false, 10));
+	}
+
+	#[test]
+	fn base64_includes_stream_data() {
+		let bytes = sample();
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		assert!(matches!(cfb.manifest(DataEncoding::Base64), Err(CfbError::DataNotLoaded { .. })));
+
+		let cfb = CompoundFile::parse_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		let manifest = cfb.manifest(DataEncoding::Base64).unwrap();
+		assert_eq!(entry(&manifest, "/Storage").data, None);
+		assert_eq!(entry(&manifest, "/Storage/Small").data, Some(BASE64.encode([1; 100])));
+		assert_eq!(entry(&manifest, "/Large").data, Some(BASE64.encode([2; 5000])));
+	}
+
+	#[test]
+	fn json_round_trip() {
+		let cfb = CompoundFile::parse_from_reader(&mut Cursor::new(sample())).unwrap();
+		for encoding in [DataEncoding::Omit, DataEncoding::Base64] {
+			let manifest = cfb.manifest(encoding).unwrap();
+			let parsed: Manifest = serde_json::from_str(&manifest.to_json().unwrap()).unwrap();
+			assert_eq!(parsed, manifest);
+		}
+	}
+}
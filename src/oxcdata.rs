@@ -1,4 +1,5 @@
-use crate::dir::DirectoryEntry;
+use crate::dir::{DirectoryEntry, EntryName};
+use crate::error::MsgError;
 use crate::oxcmsg::PropertyId;
 
 use std::rc::Rc;
@@ -16,46 +17,59 @@ use nom::{
 	sequence::terminated,
 };
 
+// seconds between the FILETIME epoch, 1601-01-01, and the Unix epoch
+const FILETIME_UNIX_EPOCH_SECONDS: i64 = 11_644_473_600;
+const FILETIME_TICKS_PER_SECOND: i64 = 10_000_000;
+
 pub fn date(input: &[u8]) -> IResult<&[u8], DateTime<Utc>> {
 	map_opt(
 		le_i64,
-		|hundred_ns_intervals| Utc.ymd(1601, 1, 1)
-			.and_hms(0, 0, 0)
-			.checked_add_signed(Duration::microseconds(hundred_ns_intervals / 10))
+		|hundred_ns_intervals| Utc.with_ymd_and_hms(1601, 1, 1, 0, 0, 0)
+			.single()?
+			.checked_add_signed(Duration::seconds(hundred_ns_intervals.div_euclid(FILETIME_TICKS_PER_SECOND)))?
+			.checked_add_signed(Duration::nanoseconds(hundred_ns_intervals.rem_euclid(FILETIME_TICKS_PER_SECOND) * 100))
 	)(input)
 }
 
 pub fn date_opt(input: &[u8]) -> IResult<&[u8], Option<DateTime<Utc>>> {
 	alt((
 		map(tag([0u8; 8]), |_| None),
-		map(date, |value| Some(value)),
+		map(date, Some),
 	))(input)
 }
 
+// Converts datetime to a FILETIME, the number of 100 ns intervals since
+// 1601-01-01, or None if it does not fit.
+pub fn filetime(datetime: &DateTime<Utc>) -> Option<i64> {
+	datetime.timestamp()
+		.checked_add(FILETIME_UNIX_EPOCH_SECONDS)?
+		.checked_mul(FILETIME_TICKS_PER_SECOND)?
+		.checked_add(datetime.timestamp_subsec_nanos() as i64 / 100)
+		.filter(|&filetime| filetime >= 0)
+}
+
 pub fn fdate(input: &[u8]) -> IResult<&[u8], DateTime<Utc>> {
 	map_opt(
 		le_f64,
-		|days| Utc.ymd(1899, 12, 30)
-			.and_hms(0, 0, 0)
+		|days| Utc.with_ymd_and_hms(1899, 12, 30, 0, 0, 0)
+			.single()?
 			.checked_add_signed(Duration::days(days.trunc() as i64))
-			.map(|datetime| datetime
-				.checked_add_signed(Duration::hours((days.fract() * 24.0).round() as i64))
-			)
-			.flatten()
+			.and_then(|datetime| datetime
+				.checked_add_signed(Duration::hours((days.fract() * 24.0).round() as i64)))
 	)(input)
 }
 
 pub fn binary(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
 	let (input, c) = le_u16(input)?;
-	map(take(c), |b| Vec::from(b))(input)
+	map(take(c), Vec::from)(input)
 }
 
 pub fn guid(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
-	map(take(16usize), |b| Vec::from(b))(input)
+	map(take(16usize), Vec::from)(input)
 }
 
 pub fn null_terminated_string(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
-	terminated(map(take_until("\x00"), |b| Vec::from(b)), take(1usize))(input)
+	terminated(map(take_until("\x00"), Vec::from), take(1usize))(input)
 }
 
 pub fn utf16le_string(len: usize) -> impl Fn(&[u8]) -> IResult<&[u8], String> {
@@ -65,7 +79,7 @@ pub fn utf16le_string(len: usize) -> impl Fn(&[u8]) -> IResult<&[u8], String> {
 }
 
 pub fn complete_utf16le_string(input: &[u8]) -> IResult<&[u8], String> {
-	if input.len() == 0 {
+	if input.is_empty() {
 		return Ok((input, String::new()))
 	}
 	if let Some(terminator_chunk) = input.chunks_exact(2).position(|chunk| chunk == b"\x00\x00") {
@@ -235,37 +249,35 @@ impl PropertyType {
 	}
 
 	pub fn is_variable(&self) -> bool {
-		match self {
-			Self::Known(KnownPropertyType::String) => true,
-			Self::Known(KnownPropertyType::String8) => true,
-			Self::Known(KnownPropertyType::Binary) => true,
-			Self::Known(KnownPropertyType::Guid) => true,
-			Self::Known(KnownPropertyType::Object) => true,
-			Self::Known(KnownPropertyType::ServerId) => true,
-			Self::Known(KnownPropertyType::RuleAction) => true,
-			Self::Known(KnownPropertyType::Restriction) => true,
-			Self::Known(KnownPropertyType::MultipleString) => true,
-			Self::Known(KnownPropertyType::MultipleString8) => true,
-			_ => false,
-		}
+		matches!(self,
+			Self::Known(KnownPropertyType::String)
+			| Self::Known(KnownPropertyType::String8)
+			| Self::Known(KnownPropertyType::Binary)
+			| Self::Known(KnownPropertyType::Guid)
+			| Self::Known(KnownPropertyType::Object)
+			| Self::Known(KnownPropertyType::ServerId)
+			| Self::Known(KnownPropertyType::RuleAction)
+			| Self::Known(KnownPropertyType::Restriction)
+			| Self::Known(KnownPropertyType::MultipleString)
+			| Self::Known(KnownPropertyType::MultipleString8)
+		)
 	}
 
 	pub fn is_multi(&self) -> bool {
-		match self {
-			Self::Known(KnownPropertyType::MultipleInteger16) => true,
-			Self::Known(KnownPropertyType::MultipleInteger32) => true,
-			Self::Known(KnownPropertyType::MultipleInteger64) => true,
-			Self::Known(KnownPropertyType::MultipleFloating32) => true,
-			Self::Known(KnownPropertyType::MultipleFloating64) => true,
-			Self::Known(KnownPropertyType::MultipleCurrency) => true,
-			Self::Known(KnownPropertyType::MultipleFloatingTime) => true,
-			Self::Known(KnownPropertyType::MultipleTime) => true,
-			Self::Known(KnownPropertyType::MultipleGuid) => true,
-			Self::Known(KnownPropertyType::MultipleBinary) => true,
-			Self::Known(KnownPropertyType::MultipleString) => true,
-			Self::Known(KnownPropertyType::MultipleString8) => true,
-			_ => false,
-		}
+		matches!(self,
+			Self::Known(KnownPropertyType::MultipleInteger16)
+			| Self::Known(KnownPropertyType::MultipleInteger32)
+			| Self::Known(KnownPropertyType::MultipleInteger64)
+			| Self::Known(KnownPropertyType::MultipleFloating32)
+			| Self::Known(KnownPropertyType::MultipleFloating64)
+			| Self::Known(KnownPropertyType::MultipleCurrency)
+			| Self::Known(KnownPropertyType::MultipleFloatingTime)
+			| Self::Known(KnownPropertyType::MultipleTime)
+			| Self::Known(KnownPropertyType::MultipleGuid)
+			| Self::Known(KnownPropertyType::MultipleBinary)
+			| Self::Known(KnownPropertyType::MultipleString)
+			| Self::Known(KnownPropertyType::MultipleString8)
+		)
 	}
 }
 
@@ -277,8 +289,8 @@ pub struct PropertyTag {
 
 impl PropertyTag {
 	pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
-		let (input, prop_type) = map(le_u16, |prop_type| PropertyType::from_u16(prop_type))(input)?;
-		let (input, id) = map(le_u16, |id| PropertyId::from_u16(id))(input)?;
+		let (input, prop_type) = map(le_u16, PropertyType::from_u16)(input)?;
+		let (input, id) = map(le_u16, PropertyId::from_u16)(input)?;
 		Ok((input, Self {
 			id,
 			prop_type,
@@ -365,41 +377,41 @@ impl PropertyValue {
 			PropertyType::Unknown(_) => Ok((input, Self::Unspecified(Vec::new()))),
 			PropertyType::Known(KnownPropertyType::Unspecified) => Ok((input, Self::Unspecified(Vec::new()))),
 			PropertyType::Known(KnownPropertyType::Null) => Ok((input, Self::Null)),
-			PropertyType::Known(KnownPropertyType::Integer16) => map(le_i16, |value| Self::Integer16(value))(input),
-			PropertyType::Known(KnownPropertyType::Integer32) => map(le_i32, |value| Self::Integer32(value))(input),
-			PropertyType::Known(KnownPropertyType::Integer64) => map(le_i64, |value| Self::Integer64(value))(input),
-			PropertyType::Known(KnownPropertyType::Floating32) => map(le_f32, |value| Self::Floating32(value))(input),
-			PropertyType::Known(KnownPropertyType::Floating64) => map(le_f64, |value| Self::Floating64(value))(input),
-			PropertyType::Known(KnownPropertyType::FloatingTime) => map(fdate, |datetime| Self::FloatingTime(datetime))(input),
-			PropertyType::Known(KnownPropertyType::Currency) => map(le_i64, |value| Self::Currency(value))(input),
-			PropertyType::Known(KnownPropertyType::ErrorCode) => map(le_u32, |value| Self::ErrorCode(value))(input),
+			PropertyType::Known(KnownPropertyType::Integer16) => map(le_i16, Self::Integer16)(input),
+			PropertyType::Known(KnownPropertyType::Integer32) => map(le_i32, Self::Integer32)(input),
+			PropertyType::Known(KnownPropertyType::Integer64) => map(le_i64, Self::Integer64)(input),
+			PropertyType::Known(KnownPropertyType::Floating32) => map(le_f32, Self::Floating32)(input),
+			PropertyType::Known(KnownPropertyType::Floating64) => map(le_f64, Self::Floating64)(input),
+			PropertyType::Known(KnownPropertyType::FloatingTime) => map(fdate, Self::FloatingTime)(input),
+			PropertyType::Known(KnownPropertyType::Currency) => map(le_i64, Self::Currency)(input),
+			PropertyType::Known(KnownPropertyType::ErrorCode) => map(le_u32, Self::ErrorCode)(input),
 			PropertyType::Known(KnownPropertyType::Boolean) => map(u8, |value| Self::Boolean(value == 1))(input),
 			PropertyType::Known(KnownPropertyType::Object) => { Ok((input, Self::Object(Vec::new()))) }
-			PropertyType::Known(KnownPropertyType::String) => map(complete_utf16le_string, |value| Self::String(value))(input),
-			PropertyType::Known(KnownPropertyType::String8) => map(null_terminated_string, |value| Self::String8(value))(input),
-			PropertyType::Known(KnownPropertyType::Time) => map(date, |datetime| Self::Time(datetime))(input),
-			PropertyType::Known(KnownPropertyType::Guid) => map(guid, |value| Self::Guid(value))(input),
-			PropertyType::Known(KnownPropertyType::ServerId) => map(ServerId::parse, |value| Self::ServerId(value))(input),
+			PropertyType::Known(KnownPropertyType::String) => map(complete_utf16le_string, Self::String)(input),
+			PropertyType::Known(KnownPropertyType::String8) => map(null_terminated_string, Self::String8)(input),
+			PropertyType::Known(KnownPropertyType::Time) => map(date, Self::Time)(input),
+			PropertyType::Known(KnownPropertyType::Guid) => map(guid, Self::Guid)(input),
+			PropertyType::Known(KnownPropertyType::ServerId) => map(ServerId::parse, Self::ServerId)(input),
 			PropertyType::Known(KnownPropertyType::Restriction) => Ok((input, Self::Restriction(Vec::new()))),
-			PropertyType::Known(KnownPropertyType::RuleAction) => map(binary, |value| Self::RuleAction(value))(input),
+			PropertyType::Known(KnownPropertyType::RuleAction) => map(binary, Self::RuleAction)(input),
 			PropertyType::Known(KnownPropertyType::Binary) => Ok((&input[input.len()..], Self::Binary(Vec::from(input)))),
-			PropertyType::Known(KnownPropertyType::MultipleInteger16) => map(many0(le_i16), |value| Self::MultipleInteger16(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleInteger32) => map(many0(le_i32), |value| Self::MultipleInteger32(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleInteger64) => map(many0(le_i64), |value| Self::MultipleInteger64(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleFloating32) => map(many0(le_f32), |value| Self::MultipleFloating32(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleFloating64) => map(many0(le_f64), |value| Self::MultipleFloating64(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleCurrency) => map(many0(le_i64), |value| Self::MultipleCurrency(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleFloatingTime) => map(many0(fdate), |value| Self::MultipleFloatingTime(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleString) => map(complete_many_utf16le_string, |value| Self::MultipleString(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleString8) => map(many0(null_terminated_string), |value| Self::MultipleString8(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleTime) => map(many0(date), |value| Self::MultipleTime(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleGuid) => map(many0(guid), |value| Self::MultipleGuid(value))(input),
-			PropertyType::Known(KnownPropertyType::MultipleBinary) => map(many0(binary), |value| Self::MultipleBinary(value))(input),
+			PropertyType::Known(KnownPropertyType::MultipleInteger16) => map(many0(le_i16), Self::MultipleInteger16)(input),
+			PropertyType::Known(KnownPropertyType::MultipleInteger32) => map(many0(le_i32), Self::MultipleInteger32)(input),
+			PropertyType::Known(KnownPropertyType::MultipleInteger64) => map(many0(le_i64), Self::MultipleInteger64)(input),
+			PropertyType::Known(KnownPropertyType::MultipleFloating32) => map(many0(le_f32), Self::MultipleFloating32)(input),
+			PropertyType::Known(KnownPropertyType::MultipleFloating64) => map(many0(le_f64), Self::MultipleFloating64)(input),
+			PropertyType::Known(KnownPropertyType::MultipleCurrency) => map(many0(le_i64), Self::MultipleCurrency)(input),
+			PropertyType::Known(KnownPropertyType::MultipleFloatingTime) => map(many0(fdate), Self::MultipleFloatingTime)(input),
+			PropertyType::Known(KnownPropertyType::MultipleString) => map(complete_many_utf16le_string, Self::MultipleString)(input),
+			PropertyType::Known(KnownPropertyType::MultipleString8) => map(many0(null_terminated_string), Self::MultipleString8)(input),
+			PropertyType::Known(KnownPropertyType::MultipleTime) => map(many0(date), Self::MultipleTime)(input),
+			PropertyType::Known(KnownPropertyType::MultipleGuid) => map(many0(guid), Self::MultipleGuid)(input),
+			PropertyType::Known(KnownPropertyType::MultipleBinary) => map(many0(binary), Self::MultipleBinary)(input),
 		}
 	}
 }
 
-pub const SUB_PREFIX: &'static str = "__substg1.0_";
+pub const SUB_PREFIX: &str = "__substg1.0_";
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct PropertyEntry {
@@ -409,64 +421,43 @@ pub struct PropertyEntry {
 }
 
 impl PropertyEntry {
-	pub fn parse<'a>(input: &'a [u8], parent_dir: &Rc<DirectoryEntry>) -> IResult<&'a [u8], Self> {
+	pub fn parse<'a>(input: &'a [u8], parent_dir: &Rc<DirectoryEntry>) -> IResult<&'a [u8], Self, MsgError> {
 		let (input, entry) = take(16usize)(input)?;
 		let (entry, tag_raw) = take(4usize)(entry)?;
-		let (_, tag) = PropertyTag::parse(tag_raw)?;
+		let (_, tag) = PropertyTag::parse(tag_raw).map_err(|err| err.map(MsgError::from))?;
 		let (_, tag_raw) = le_u32(tag_raw)?;
 		let (entry, flags) = le_u32(entry)?;
+		// paths are relative to the storage until MsgError::in_storage qualifies them
+		let invalid_value = |path: String| move |_| nom::Err::Failure(MsgError::InvalidPropertyValue { tag: tag_raw, path });
 		let value = if tag.prop_type.is_multi() || tag.prop_type.is_variable() {
 			// variable-length value
 			let (_entry, size) = le_u32(entry)?;
 			// let (_entry, reserved) = le_u32(_entry)?;
-			let stream_name = format!("{}{:08X}", SUB_PREFIX, tag_raw);
-			if let Some(bytes) = parent_dir.children.borrow().get(&stream_name).map(|child| child.data.borrow()) {
-				if bytes.len() != size as usize && ((tag.prop_type == PropertyType::Known(KnownPropertyType::String) && bytes.len() + 2 != size as usize) || (tag.prop_type == PropertyType::Known(KnownPropertyType::String8) && bytes.len() + 1 != size as usize)) {
-					eprintln!("[debug] Stream {:?} invalid size {}, expected {}", &stream_name, bytes.len(), size);
-					return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Fail)));
-				}
-				if tag.prop_type.is_multi() && tag.prop_type.is_variable() {
-					let len_item_size = if tag.prop_type == PropertyType::Known(KnownPropertyType::MultipleBinary) { 8usize } else { 4usize };
-					let value_lengths: Vec<u32> = bytes.chunks(len_item_size).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect();
-					let mut value_bytes = Vec::new();
-					for i in 0..value_lengths.len() {
-						let index_stream_name = format!("{}-{:08X}", stream_name, i);
-						if let Some(index_stream_bytes) = parent_dir.children.borrow().get(&index_stream_name).map(|child| child.data.borrow()) {
-							value_bytes.extend_from_slice(&index_stream_bytes);
-						} else {
-							eprintln!("[debug] Could not find stream {:?} in parent {:?}", &index_stream_name, &parent_dir.name);
-							return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Fail)));
-						}
-					}
-					match PropertyValue::parse(&value_bytes, tag.prop_type) {
-						Ok((_, value)) => value,
-						Err(e) => {
-							eprintln!("[debug] Could not parse property value of type {:?}: {}", tag.prop_type, e);
-							return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Fail)))
-						}
-					}
-				} else {
-					match PropertyValue::parse(&bytes, tag.prop_type) {
-						Ok((_, value)) => value,
-						Err(e) => {
-							eprintln!("[debug] Could not parse property value of type {:?}: {}", tag.prop_type, e);
-							return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Fail)))
-						}
-					}
+			let stream_name = EntryName::from(format!("{}{:08X}", SUB_PREFIX, tag_raw));
+			let children = parent_dir.children.borrow();
+			let bytes = children.get(&stream_name)
+				.ok_or_else(|| nom::Err::Failure(MsgError::MissingStream { path: stream_name.to_string() }))?
+				.data.borrow();
+			if bytes.len() != size as usize && ((tag.prop_type == PropertyType::Known(KnownPropertyType::String) && bytes.len() + 2 != size as usize) || (tag.prop_type == PropertyType::Known(KnownPropertyType::String8) && bytes.len() + 1 != size as usize)) {
+				return Err(nom::Err::Failure(MsgError::PropertySizeMismatch { tag: tag_raw, path: stream_name.to_string(), expected: size as usize, actual: bytes.len() }));
+			}
+			if tag.prop_type.is_multi() && tag.prop_type.is_variable() {
+				let len_item_size = if tag.prop_type == PropertyType::Known(KnownPropertyType::MultipleBinary) { 8usize } else { 4usize };
+				let value_lengths: Vec<u32> = bytes.chunks_exact(len_item_size).map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect();
+				let mut value_bytes = Vec::new();
+				for i in 0..value_lengths.len() {
+					let index_stream_name = EntryName::from(format!("{}-{:08X}", stream_name, i));
+					let index_stream = children.get(&index_stream_name)
+						.ok_or_else(|| nom::Err::Failure(MsgError::MissingStream { path: index_stream_name.to_string() }))?;
+					value_bytes.extend_from_slice(&index_stream.data.borrow());
 				}
+				PropertyValue::parse(&value_bytes, tag.prop_type).map_err(invalid_value(stream_name.to_string()))?.1
 			} else {
-				eprintln!("[debug] Could not find stream {:?} in parent {:?}", &stream_name, &parent_dir.name);
-				return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Fail)));
+				PropertyValue::parse(&bytes, tag.prop_type).map_err(invalid_value(stream_name.to_string()))?.1
 			}
 		} else {
 			// fixed-length value
-			match PropertyValue::parse(entry, tag.prop_type) {
-				Ok((_, value)) => value,
-				Err(e) => {
-					eprintln!("[debug] Could not parse property value of type {:?}: {}", tag.prop_type, e);
-					return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Fail)))
-				}
-			}
+			PropertyValue::parse(entry, tag.prop_type).map_err(invalid_value(crate::oxmsg::PROPERTY_STREAM_NAME.to_string()))?.1
 		};
 		Ok((input, Self { tag, flags, value }))
 	}
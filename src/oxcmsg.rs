@@ -539,7 +539,7 @@ impl<'a> Message<'a> {
 		};
 		let client_submit_time = if let Some(value) = self.client_submit_time {
 			if let PropertyValue::Time(value) = &value.value {
-				Some(value.clone())
+				Some(*value)
 			} else {
 				None
 			}
@@ -575,7 +575,7 @@ impl<'a> Message<'a> {
 		};
 		let delivery_time = if let Some(value) = self.delivery_time {
 			if let PropertyValue::Time(value) = &value.value {
-				Some(value.clone())
+				Some(*value)
 			} else {
 				None
 			}
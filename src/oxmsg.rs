@@ -2,20 +2,22 @@ use crate::oxcdata::PropertyEntry;
 use crate::oxcmsg::PropertyId;
 use crate::dir::DirectoryEntry;
 use crate::cfb::CompoundFile;
-use crate::error::{BoxError, BoxResult};
+use crate::shared::SharedCompoundFile;
+use crate::error::{MsgError, MsgResult};
 
 use std::collections::BTreeMap;
 use std::rc::Rc;
 
 use nom::{
 	IResult,
+	error::ErrorKind,
 	multi::fold_many_m_n,
 	combinator::{map, map_res},
 	bytes::streaming::take,
 	number::streaming::le_u32,
 };
 
-pub const PROPERTY_STREAM_NAME: &'static str = "__properties_version1.0";
+pub const PROPERTY_STREAM_NAME: &str = "__properties_version1.0";
 pub const TOPLEVEL_HEADER_SIZE: usize = 32;
 pub const RECIP_OR_ATTACH_HEADER_SIZE: usize = 8;
 pub const EMBEDDED_MSG_HEADER_SIZE: usize = 24;
@@ -53,16 +55,16 @@ pub struct PropertyStream {
 }
 
 impl PropertyStream {
-	pub fn parse<'a>(input: &'a [u8], header_size: usize, parent_dir: &'a Rc<DirectoryEntry>) -> IResult<&'a [u8], Self> {
+	pub fn parse<'a>(input: &'a [u8], header_size: usize, parent_dir: &'a Rc<DirectoryEntry>) -> IResult<&'a [u8], Self, MsgError> {
 		let (input, header_bytes) = take(header_size)(input)?;
 		let header = if header_size >= EMBEDDED_MSG_HEADER_SIZE {
-			let (_, header) = PropertyStreamHeader::parse(header_bytes)?;
+			let (_, header) = PropertyStreamHeader::parse(header_bytes).map_err(|err| err.map(MsgError::from))?;
 			header
 		} else {
 			PropertyStreamHeader::default()
 		};
 		if input.len() % 16 != 0 {
-			return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Count)));
+			return Err(nom::Err::Failure(MsgError::Parse(ErrorKind::Count)));
 		}
 		let num = input.len() / 16;
 		map(fold_many_m_n(num, num, |input| PropertyEntry::parse(input, parent_dir), BTreeMap::new, |mut map, prop| {
@@ -81,26 +83,23 @@ pub struct MsgFile {
 }
 
 impl MsgFile {
-	pub fn from_cfb(cfb: CompoundFile) -> BoxResult<Self> {
-		let root_dir = &cfb.dirs[0];
-		let properties = if let Some(property_stream) = root_dir.children.borrow().get(PROPERTY_STREAM_NAME) {
-			let (_, properties) = PropertyStream::parse(&property_stream.data.borrow(), TOPLEVEL_HEADER_SIZE, root_dir).map_err(|err| BoxError::from(err.to_owned()))?;
+	pub fn from_cfb(cfb: CompoundFile) -> MsgResult<Self> {
+		let root_dir = cfb.open_storage("/")?;
+		let property_stream_path = format!("/{}", PROPERTY_STREAM_NAME);
+		let properties = if let Ok(property_stream) = cfb.open_stream(&property_stream_path) {
+			let (_, properties) = PropertyStream::parse(&property_stream.data.borrow(), TOPLEVEL_HEADER_SIZE, &root_dir).map_err(|err| MsgError::from(err).in_storage(""))?;
 			properties
 		} else {
-			return Err("Property stream missing".into())
+			return Err(MsgError::MissingStream { path: property_stream_path })
 		};
 
 		let mut recipients = Vec::new();
 		let mut i: u32 = 0;
 		loop {
-			let dir_name = format!("__recip_version1.0_#{:08X}", i);
-			if let Some(dir_entry) = root_dir.children.borrow().get(&dir_name) {
-				if let Some(property_stream) = dir_entry.children.borrow().get(PROPERTY_STREAM_NAME) {
-					let (_, recipient) = PropertyStream::parse(&property_stream.data.borrow(), RECIP_OR_ATTACH_HEADER_SIZE, dir_entry).map_err(|err| BoxError::from(err.to_owned()))?;
-					recipients.push(recipient);
-				} else {
-					break;
-				}
+			let dir_path = format!("/__recip_version1.0_#{:08X}", i);
+			if let (Ok(dir_entry), Ok(property_stream)) = (cfb.open_storage(&dir_path), cfb.open_stream(&format!("{}/{}", dir_path, PROPERTY_STREAM_NAME))) {
+				let (_, recipient) = PropertyStream::parse(&property_stream.data.borrow(), RECIP_OR_ATTACH_HEADER_SIZE, &dir_entry).map_err(|err| MsgError::from(err).in_storage(&dir_path))?;
+				recipients.push(recipient);
 			} else {
 				break;
 			}
@@ -110,14 +109,10 @@ impl MsgFile {
 		let mut attachments = Vec::new();
 		i = 0;
 		loop {
-			let dir_name = format!("__attach_version1.0_#{:08X}", i);
-			if let Some(dir_entry) = root_dir.children.borrow().get(&dir_name) {
-				if let Some(property_stream) = dir_entry.children.borrow().get(PROPERTY_STREAM_NAME) {
-					let (_, attachment) = PropertyStream::parse(&property_stream.data.borrow(), RECIP_OR_ATTACH_HEADER_SIZE, dir_entry).map_err(|err| BoxError::from(err.to_owned()))?;
-					attachments.push(attachment);
-				} else {
-					break;
-				}
+			let dir_path = format!("/__attach_version1.0_#{:08X}", i);
+			if let (Ok(dir_entry), Ok(property_stream)) = (cfb.open_storage(&dir_path), cfb.open_stream(&format!("{}/{}", dir_path, PROPERTY_STREAM_NAME))) {
+				let (_, attachment) = PropertyStream::parse(&property_stream.data.borrow(), RECIP_OR_ATTACH_HEADER_SIZE, &dir_entry).map_err(|err| MsgError::from(err).in_storage(&dir_path))?;
+				attachments.push(attachment);
 			} else {
 				break;
 			}
@@ -126,4 +121,25 @@ impl MsgFile {
 
 		Ok(Self { cfb, properties, recipients, attachments })
 	}
-}
\ No newline at end of file
+}
+
+// Send and Sync counterpart of MsgFile, whose compound file is a
+// SharedCompoundFile, so that attachments can be processed in parallel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedMsgFile {
+	pub cfb: SharedCompoundFile,
+	pub properties: PropertyStream,
+	pub recipients: Vec<PropertyStream>,
+	pub attachments: Vec<PropertyStream>,
+}
+
+impl From<MsgFile> for SharedMsgFile {
+	fn from(msg: MsgFile) -> Self {
+		Self {
+			cfb: msg.cfb.into(),
+			properties: msg.properties,
+			recipients: msg.recipients,
+			attachments: msg.attachments,
+		}
+	}
+}
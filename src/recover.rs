@@ -0,0 +1,220 @@
+use crate::cfb::{self, CompoundFile, CompoundFileHeader, HeaderValidation, HEADER_SIZE};
+use crate::dir;
+use crate::error::{CfbError, CfbResult};
+use crate::fat;
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+	// all stream_size bytes were read
+	Complete,
+	// only a prefix of the stream could be read
+	Truncated,
+	// none of the stream's bytes could be read
+	Unreadable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveredFile {
+	pub cfb: CompoundFile,
+	// state of every stream object in cfb.dirs, by directory entry ID
+	pub streams: BTreeMap<u32, StreamState>,
+}
+
+// Reads as much of sector as the file holds into buf, pads the rest of the
+// sector with FREESECT bytes and returns the number of bytes actually read.
+pub(crate) fn read_sector<R: Read + Seek>(reader: &mut R, buf: &mut Vec<u8>, header: &CompoundFileHeader, sector: u32) -> usize {
+	buf.clear();
+	if sector <= fat::MAXREGSECT {
+		let offset = header.sector_offset(sector) as u64;
+		let _ = reader.seek(SeekFrom::Start(offset)).and_then(|_| reader.by_ref().take(header.sector_size() as u64).read_to_end(buf));
+	}
+	let read = buf.len();
+	buf.resize(header.sector_size(), 0xFF);
+	read
+}
+
+// Reads the readable prefix of a stream stored in the FAT, following its chain
+// until the chain breaks, a sector cannot be read in full or size is reached.
+pub(crate) fn read_fat_stream<R: Read + Seek>(reader: &mut R, buf: &mut Vec<u8>, header: &CompoundFileHeader, entries: &[u32], sector: u32, size: usize) -> Vec<u8> {
+	let (chain, _) = fat::get_partial_chain(entries, sector);
+	let mut bytes = Vec::new();
+	for sector in chain {
+		if bytes.len() >= size {
+			break;
+		}
+		let read = read_sector(reader, buf, header, sector);
+		bytes.extend_from_slice(&buf[..read]);
+		if read < header.sector_size() {
+			break;
+		}
+	}
+	bytes.truncate(size);
+	bytes
+}
+
+fn read_mini_stream(entries: &[u32], mini_stream_bytes: &[u8], sector: u32, size: usize) -> Vec<u8> {
+	let (chain, _) = fat::get_partial_chain(entries, sector);
+	let mut bytes = Vec::new();
+	for sector in chain {
+		if bytes.len() >= size {
+			break;
+		}
+		let start = (sector as usize) * fat::MINIFAT_SECTOR_SIZE;
+		let sector_bytes = mini_stream_bytes.get(start..).unwrap_or_default();
+		let sector_bytes = &sector_bytes[..sector_bytes.len().min(fat::MINIFAT_SECTOR_SIZE)];
+		bytes.extend_from_slice(sector_bytes);
+		if sector_bytes.len() < fat::MINIFAT_SECTOR_SIZE {
+			break;
+		}
+	}
+	bytes.truncate(size);
+	bytes
+}
+
+// Parses the metadata of a compound file like
+// CompoundFile::parse_metadata_from_reader, but treats unreadable sectors as
+// unallocated, follows broken chains only as far as they are valid and skips
+// invalid directory links. Only a damaged header or a missing root entry fail
+// the parse.
+pub(crate) fn recover_metadata<R: Read + Seek>(reader: &mut R) -> CfbResult<CompoundFile> {
+	let mut buf = vec![0u8; HEADER_SIZE];
+
+	// read header
+	reader.read_exact(&mut buf)?;
+	let header = cfb::parse_header(&buf, HeaderValidation::Lenient)?;
+	let sector_size = header.sector_size();
+	let file_sectors = reader.seek(SeekFrom::End(0))?.saturating_sub(sector_size as u64).div_ceil(sector_size as u64);
+
+	// get DIFAT, up to the first DIFAT sector that cannot be read in full
+	let mut difat: Vec<u32> = header.difat.iter().copied().take_while(|&sector| sector != fat::FREESECT).collect();
+	let mut difat_sectors = Vec::new();
+	let mut visited = HashSet::new();
+	let mut sector = header.difat_first_sector;
+	while sector != fat::ENDOFCHAIN && visited.insert(sector) && read_sector(reader, &mut buf, &header, sector) == sector_size {
+		difat_sectors.push(sector);
+		let mut entries = Vec::new();
+		cfb::extend_fat(&buf, &header, sector, &mut entries)?;
+		sector = entries.pop().unwrap_or(fat::ENDOFCHAIN);
+		difat.extend(entries.into_iter().take_while(|&sector| sector != fat::FREESECT));
+	}
+	// a file cannot hold more FAT sectors than sectors
+	difat.truncate(file_sectors as usize);
+
+	// get FAT, reading unreadable parts as unallocated
+	let mut fat = Vec::new();
+	for &sector in &difat {
+		read_sector(reader, &mut buf, &header, sector);
+		cfb::extend_fat(&buf, &header, sector, &mut fat)?;
+	}
+
+	// get MiniFAT
+	let mut minifat = Vec::new();
+	for sector in fat::get_partial_chain(&fat, header.minifat_first_sector).0 {
+		read_sector(reader, &mut buf, &header, sector);
+		cfb::extend_fat(&buf, &header, sector, &mut minifat)?;
+	}
+
+	// get DirectoryEntry, keeping IDs stable by filling unreadable entries with unallocated ones
+	let mut dirs = Vec::new();
+	for sector in fat::get_partial_chain(&fat, header.dir_first_sector).0 {
+		let read = read_sector(reader, &mut buf, &header, sector);
+		for (i, entry_bytes) in buf.chunks_exact(dir::ENTRY_SIZE).enumerate() {
+			let entry = if (i + 1) * dir::ENTRY_SIZE <= read {
+				dir::DirectoryEntry::parse(entry_bytes).map(|(_, entry)| entry).unwrap_or_default()
+			} else {
+				dir::DirectoryEntry::default()
+			};
+			dirs.push(Rc::new(entry));
+		}
+	}
+	if dirs.first().map_or(true, |root| root.object_type != dir::OBJECT_ROOT_STORAGE) {
+		return Err(CfbError::MissingRootEntry);
+	}
+	cfb::set_entry_children(&dirs, 0, false)?;
+
+	Ok(CompoundFile {
+		header,
+		difat,
+		difat_sectors,
+		fat,
+		minifat,
+		dirs,
+	})
+}
+
+// Parses a compound file like CompoundFile::parse_from_reader, as tolerantly
+// as recover_metadata, and reads the readable prefix of every stream.
+pub fn recover<R: Read + Seek>(reader: &mut R) -> CfbResult<RecoveredFile> {
+	let cfb = recover_metadata(reader)?;
+	let header = &cfb.header;
+	let mut buf = Vec::with_capacity(header.sector_size());
+
+	// get mini stream and DirectoryEntry data
+	let root = &cfb.dirs[0];
+	let mini_stream_bytes = read_fat_stream(reader, &mut buf, header, &cfb.fat, root.starting_sector, root.stream_size as usize);
+	let mut streams = BTreeMap::new();
+	for (id, entry) in cfb.dirs.iter().enumerate() {
+		if entry.object_type != dir::OBJECT_STREAM {
+			continue;
+		}
+		let size = entry.stream_size as usize;
+		let data = if entry.stream_size < header.mini_stream_cutoff_size as u64 {
+			read_mini_stream(&cfb.minifat, &mini_stream_bytes, entry.starting_sector, size)
+		} else {
+			read_fat_stream(reader, &mut buf, header, &cfb.fat, entry.starting_sector, size)
+		};
+		let state = if data.len() == size {
+			StreamState::Complete
+		} else if data.is_empty() {
+			StreamState::Unreadable
+		} else {
+			StreamState::Truncated
+		};
+		streams.insert(id as u32, state);
+		*entry.data.borrow_mut() = data;
+	}
+
+	Ok(RecoveredFile { cfb, streams })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::builder::CompoundFileBuilder;
+
+	use std::io::Cursor;
+
+	#[test]
+	fn recover_truncated_file() {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Small", vec![1; 100]).unwrap();
+		builder.create_stream("/First", vec![2; 5000]).unwrap();
+		builder.create_stream("/Second", vec![3; 5000]).unwrap();
+		let cfb = builder.build().unwrap();
+		let mut bytes = Cursor::new(Vec::new());
+		cfb.write_to(&mut bytes).unwrap();
+		let mut bytes = bytes.into_inner();
+		let id = |name: &str| cfb.dirs.iter().position(|entry| entry.name == name).unwrap() as u32;
+
+		let recovered = recover(&mut Cursor::new(&bytes)).unwrap();
+		assert!(recovered.streams.values().all(|&state| state == StreamState::Complete));
+		assert_eq!(recovered.cfb.dirs, cfb.dirs);
+
+		// cut the file in the middle of the first of the two large streams
+		let second = cfb.open_stream("/Second").unwrap().starting_sector;
+		let first = cfb.open_stream("/First").unwrap().starting_sector;
+		assert!(first < second);
+		bytes.truncate(cfb.header.sector_offset(first + 3));
+		let recovered = recover(&mut Cursor::new(&bytes)).unwrap();
+		assert_eq!(recovered.streams[&id("Small")], StreamState::Complete);
+		assert_eq!(recovered.streams[&id("First")], StreamState::Truncated);
+		assert_eq!(recovered.streams[&id("Second")], StreamState::Unreadable);
+		assert_eq!(*recovered.cfb.open_stream("/First").unwrap().data.borrow(), vec![2; 3 * 512]);
+		assert!(CompoundFile::parse_from_reader(&mut Cursor::new(&bytes)).is_err());
+	}
+}
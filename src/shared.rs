@@ -0,0 +1,124 @@
+use crate::cfb::{CompoundFile, CompoundFileHeader};
+use crate::dir::{self, DirectoryEntry, EntryName};
+use crate::error::{CfbError, CfbResult};
+
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+// Immutable counterpart of DirectoryEntry that refers to its children by
+// directory entry ID instead of Rc, so that it is Send and Sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedEntry {
+	pub name: String,
+	pub object_type: u8,
+	pub color_flag: u8,
+	pub left_sibling_id: u32,
+	pub right_sibling_id: u32,
+	pub child_id: u32,
+	pub clsid: [u8; 16],
+	pub state_bits: u32,
+	pub creation_time: Option<DateTime<Utc>>,
+	pub modified_time: Option<DateTime<Utc>>,
+	pub starting_sector: u32,
+	pub stream_size: u64,
+	pub children: BTreeMap<EntryName, u32>,
+	pub data: Arc<[u8]>,
+}
+
+// Immutable counterpart of CompoundFile that keeps its directory entries in
+// an arena indexed by directory entry ID. It is Send and Sync, so it can be
+// wrapped in an Arc and read from several threads at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedCompoundFile {
+	pub header: CompoundFileHeader,
+	pub difat: Vec<u32>,
+	pub difat_sectors: Vec<u32>,
+	pub fat: Vec<u32>,
+	pub minifat: Vec<u32>,
+	pub dirs: Vec<SharedEntry>,
+}
+
+impl From<CompoundFile> for SharedCompoundFile {
+	fn from(cfb: CompoundFile) -> Self {
+		let ids: HashMap<*const DirectoryEntry, u32> = cfb.dirs.iter()
+			.enumerate()
+			.map(|(id, entry)| (Rc::as_ptr(entry), id as u32))
+			.collect();
+		let dirs = cfb.dirs.iter().map(|entry| SharedEntry {
+			name: entry.name.clone(),
+			object_type: entry.object_type,
+			color_flag: entry.color_flag,
+			left_sibling_id: entry.left_sibling_id,
+			right_sibling_id: entry.right_sibling_id,
+			child_id: entry.child_id,
+			clsid: entry.clsid,
+			state_bits: entry.state_bits,
+			creation_time: entry.creation_time,
+			modified_time: entry.modified_time,
+			starting_sector: entry.starting_sector,
+			stream_size: entry.stream_size,
+			children: entry.children.borrow().iter()
+				.filter_map(|(name, child)| Some((name.clone(), *ids.get(&Rc::as_ptr(child))?)))
+				.collect(),
+			data: entry.data.borrow().as_slice().into(),
+		}).collect();
+		Self {
+			header: cfb.header,
+			difat: cfb.difat,
+			difat_sectors: cfb.difat_sectors,
+			fat: cfb.fat,
+			minifat: cfb.minifat,
+			dirs,
+		}
+	}
+}
+
+impl SharedCompoundFile {
+	// Looks up a directory entry by its path from the root storage, like
+	// CompoundFile::entry.
+	pub fn entry(&self, path: &str) -> CfbResult<&SharedEntry> {
+		let mut entry = self.dirs.first().ok_or(CfbError::MissingRootEntry)?;
+		let mut entry_path = String::new();
+		for component in path.split('/').filter(|component| !component.is_empty()) {
+			entry_path.push('/');
+			entry_path.push_str(component);
+			entry = entry.children.get(&EntryName::from(component))
+				.and_then(|&id| self.dirs.get(id as usize))
+				.ok_or_else(|| CfbError::MissingEntry { path: entry_path.clone() })?;
+		}
+		Ok(entry)
+	}
+
+	pub fn open_storage(&self, path: &str) -> CfbResult<&SharedEntry> {
+		let entry = self.entry(path)?;
+		if entry.object_type != dir::OBJECT_STORAGE && entry.object_type != dir::OBJECT_ROOT_STORAGE {
+			return Err(CfbError::NotAStorage { path: path.to_string() });
+		}
+		Ok(entry)
+	}
+
+	pub fn open_stream(&self, path: &str) -> CfbResult<&SharedEntry> {
+		let entry = self.entry(path)?;
+		if entry.object_type != dir::OBJECT_STREAM {
+			return Err(CfbError::NotAStream { path: path.to_string() });
+		}
+		Ok(entry)
+	}
+
+	// Returns the children of a storage in directory name order.
+	pub fn children<'a>(&'a self, entry: &'a SharedEntry) -> impl Iterator<Item = &'a SharedEntry> + 'a {
+		entry.children.values().filter_map(|&id| self.dirs.get(id as usize))
+	}
+}
+
+// Fails to compile if a field that is not Send or Sync, such as an Rc, finds
+// its way into the shared models.
+const fn assert_send_sync<T: Send + Sync>() {}
+
+const _: () = {
+	assert_send_sync::<SharedCompoundFile>();
+	assert_send_sync::<crate::oxmsg::SharedMsgFile>();
+};
@@ -0,0 +1,240 @@
+use crate::forensic::Slack;
+
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{ready, Context, Poll};
+
+// Reads a single stream of a compound file, seeking through its sector chain
+// on demand. Created by CompoundFile::stream_reader.
+#[derive(Debug)]
+pub struct StreamReader<R> {
+	reader: R,
+	chunk_size: usize,
+	offsets: Vec<u64>, // file offset of each sector or mini sector in the chain
+	size: u64,
+	position: u64,
+}
+
+impl<R> StreamReader<R> {
+	pub(crate) fn new(reader: R, chunk_size: usize, offsets: Vec<u64>, size: u64) -> Self {
+		Self {
+			reader,
+			chunk_size,
+			offsets,
+			size,
+			position: 0,
+		}
+	}
+
+	pub fn len(&self) -> u64 {
+		self.size
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.size == 0
+	}
+
+	pub fn into_inner(self) -> R {
+		self.reader
+	}
+}
+
+impl<R: Read + Seek> StreamReader<R> {
+	// Reads the bytes between the end of the stream and the end of its last
+	// sector or mini sector.
+	pub fn read_slack(&mut self) -> io::Result<Slack> {
+		let chunk_size = self.chunk_size as u64;
+		let used = self.size % chunk_size;
+		let Some(&offset) = self.offsets.get(self.size.div_ceil(chunk_size).saturating_sub(1) as usize) else {
+			return Ok(Slack { offset: 0, data: Vec::new() });
+		};
+		if used == 0 {
+			return Ok(Slack { offset: offset + chunk_size, data: Vec::new() });
+		}
+		let mut data = Vec::with_capacity((chunk_size - used) as usize);
+		self.reader.seek(SeekFrom::Start(offset + used))?;
+		self.reader.by_ref().take(chunk_size - used).read_to_end(&mut data)?;
+		Ok(Slack { offset: offset + used, data })
+	}
+}
+
+impl<R> StreamReader<R> {
+	// Returns the file offset to read from next and how many bytes can be read
+	// there without crossing into the next sector, or None at the end of the stream.
+	fn next_read(&self, len: usize) -> Option<(u64, usize)> {
+		if self.position >= self.size || len == 0 {
+			return None;
+		}
+		let chunk = (self.position / self.chunk_size as u64) as usize;
+		let chunk_offset = (self.position % self.chunk_size as u64) as usize;
+		let len = len
+			.min(self.chunk_size - chunk_offset)
+			.min((self.size - self.position) as usize);
+		Some((self.offsets[chunk] + chunk_offset as u64, len))
+	}
+
+	// Advances past len bytes read from the underlying reader. A reader that
+	// ends before the stream does is truncated rather than done.
+	fn advance(&mut self, len: usize) -> io::Result<usize> {
+		if len == 0 {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "compound file ends within a stream"));
+		}
+		self.position += len as u64;
+		Ok(len)
+	}
+
+	fn seek_position(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+			SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+		};
+		match position {
+			Some(position) => {
+				self.position = position;
+				Ok(position)
+			}
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+		}
+	}
+}
+
+impl<R: Read + Seek> Read for StreamReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let Some((offset, len)) = self.next_read(buf.len()) else {
+			return Ok(0);
+		};
+		self.reader.seek(SeekFrom::Start(offset))?;
+		let len = self.reader.read(&mut buf[..len])?;
+		self.advance(len)
+	}
+}
+
+impl<R> Seek for StreamReader<R> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		self.seek_position(pos)
+	}
+}
+
+#[cfg(feature = "async")]
+impl<R: futures_io::AsyncRead + futures_io::AsyncSeek + Unpin> futures_io::AsyncRead for StreamReader<R> {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let Some((offset, len)) = this.next_read(buf.len()) else {
+			return Poll::Ready(Ok(0));
+		};
+		ready!(Pin::new(&mut this.reader).poll_seek(cx, SeekFrom::Start(offset)))?;
+		let len = ready!(Pin::new(&mut this.reader).poll_read(cx, &mut buf[..len]))?;
+		Poll::Ready(this.advance(len))
+	}
+}
+
+#[cfg(feature = "async")]
+impl<R: Unpin> futures_io::AsyncSeek for StreamReader<R> {
+	fn poll_seek(self: Pin<&mut Self>, _cx: &mut Context<'_>, pos: SeekFrom) -> Poll<io::Result<u64>> {
+		Poll::Ready(self.get_mut().seek_position(pos))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::CompoundFileBuilder;
+	use crate::cfb::CompoundFile;
+
+	use std::io::Cursor;
+
+	fn data(len: usize) -> Vec<u8> {
+		(0..len).map(|i| (i * 7 % 251) as u8).collect()
+	}
+
+	fn sample() -> (Vec<u8>, CompoundFile) {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_stream("/Small", data(300)).unwrap()
+			.create_stream("/Large", data(5000)).unwrap();
+		let mut cursor = Cursor::new(Vec::new());
+		builder.build().unwrap().write_to(&mut cursor).unwrap();
+		let bytes = cursor.into_inner();
+		let cfb = CompoundFile::parse_metadata_from_reader(&mut Cursor::new(&bytes)).unwrap();
+		(bytes, cfb)
+	}
+
+	fn reader<'a>(bytes: &'a [u8], cfb: &CompoundFile, path: &str) -> StreamReader<Cursor<&'a [u8]>> {
+		cfb.stream_reader(Cursor::new(bytes), &cfb.open_stream(path).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn reads_whole_streams() {
+		let (bytes, cfb) = sample();
+		for (path, len) in [("/Small", 300), ("/Large", 5000)] {
+			let mut reader = reader(&bytes, &cfb, path);
+			assert_eq!(reader.len(), len as u64);
+			let mut read = Vec::new();
+			reader.read_to_end(&mut read).unwrap();
+			assert_eq!(read, data(len));
+		}
+	}
+
+	#[test]
+	fn reads_across_sector_boundaries() {
+		let (bytes, cfb) = sample();
+		// mini sectors are 64 bytes, sectors of a version 3 file 512
+		for (path, len, start) in [("/Small", 300, 60), ("/Large", 5000, 500)] {
+			let mut reader = reader(&bytes, &cfb, path);
+			reader.seek(SeekFrom::Start(start)).unwrap();
+			let mut buf = [0u8; 30];
+			reader.read_exact(&mut buf).unwrap();
+			assert_eq!(buf, data(len)[start as usize..start as usize + 30]);
+			// a single read stops at the end of a sector
+			reader.seek(SeekFrom::Start(start)).unwrap();
+			assert!(reader.read(&mut buf).unwrap() < 30);
+		}
+	}
+
+	#[test]
+	fn seeks_relative_to_end_and_current_position() {
+		let (bytes, cfb) = sample();
+		let mut reader = reader(&bytes, &cfb, "/Large");
+		assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 4990);
+		assert_eq!(reader.seek(SeekFrom::Current(-5)).unwrap(), 4985);
+		let mut read = Vec::new();
+		reader.read_to_end(&mut read).unwrap();
+		assert_eq!(read, data(5000)[4985..]);
+		assert_eq!(reader.stream_position().unwrap(), 5000);
+		assert_eq!(reader.seek(SeekFrom::Current(-5001)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	}
+
+	#[test]
+	fn seeking_past_the_end_reads_nothing() {
+		let (bytes, cfb) = sample();
+		let mut reader = reader(&bytes, &cfb, "/Small");
+		assert_eq!(reader.seek(SeekFrom::End(100)).unwrap(), 400);
+		assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+		assert_eq!(reader.seek(SeekFrom::Start(1 << 40)).unwrap(), 1 << 40);
+		assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+	}
+
+	#[test]
+	fn read_slack_returns_bytes_after_the_stream() {
+		let (bytes, cfb) = sample();
+		// the builder pads the last sector of every stream with zeros
+		for (path, chunk_size, used) in [("/Small", 64, 300 % 64), ("/Large", 512, 5000 % 512)] {
+			let (_, offsets) = cfb.stream_offsets(&cfb.open_stream(path).unwrap()).unwrap();
+			let slack = reader(&bytes, &cfb, path).read_slack().unwrap();
+			assert_eq!(slack.offset, offsets.last().unwrap() + used as u64);
+			assert_eq!(slack.data, vec![0; chunk_size - used]);
+		}
+	}
+
+	#[test]
+	fn truncated_file_is_an_error() {
+		let (bytes, cfb) = sample();
+		let (_, offsets) = cfb.stream_offsets(&cfb.open_stream("/Large").unwrap()).unwrap();
+		let truncated = &bytes[..*offsets.last().unwrap() as usize];
+		let mut read = Vec::new();
+		let err = reader(truncated, &cfb, "/Large").read_to_end(&mut read).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+	}
+}
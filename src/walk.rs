@@ -0,0 +1,221 @@
+use crate::cfb::CompoundFile;
+use crate::dir::{self, DirectoryEntry};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalkOrder {
+	// every storage before its children
+	#[default]
+	PreOrder,
+	// every storage after its children
+	PostOrder,
+}
+
+fn is_storage(entry: &DirectoryEntry) -> bool {
+	entry.object_type == dir::OBJECT_STORAGE || entry.object_type == dir::OBJECT_ROOT_STORAGE
+}
+
+fn child_path(path: &str, name: &str) -> String {
+	format!("{}/{}", path.trim_end_matches('/'), name)
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+	id: usize,
+	path: String,
+	depth: usize,
+	expanded: bool,
+}
+
+// Iterates over the directory tree of a compound file, yielding the path,
+// depth and entry of every node. The root storage has the path "/" and depth
+// 0, and the children of a storage are visited in directory name order.
+#[derive(Debug, Clone)]
+pub struct Walker<'a> {
+	cfb: &'a CompoundFile,
+	ids: HashMap<*const DirectoryEntry, usize>,
+	order: WalkOrder,
+	stack: Vec<Frame>,
+	// storage yielded last in pre-order, whose children are pushed on the next call
+	pending: Option<Frame>,
+}
+
+impl<'a> Walker<'a> {
+	pub fn new(cfb: &'a CompoundFile, order: WalkOrder) -> Self {
+		let ids = cfb.dirs.iter().enumerate().map(|(id, entry)| (Rc::as_ptr(entry), id)).collect();
+		let stack = if cfb.dirs.is_empty() {
+			Vec::new()
+		} else {
+			vec![Frame { id: 0, path: "/".to_string(), depth: 0, expanded: false }]
+		};
+		Self { cfb, ids, order, stack, pending: None }
+	}
+
+	// Skips the children of the storage returned by the last call to next.
+	// Only has an effect in pre-order, where children come after their storage.
+	pub fn skip_subtree(&mut self) {
+		self.pending = None;
+	}
+
+	fn push_children(&mut self, frame: &Frame) {
+		let children = self.cfb.dirs[frame.id].children.borrow();
+		for (name, child) in children.iter().rev() {
+			if let Some(&id) = self.ids.get(&Rc::as_ptr(child)) {
+				self.stack.push(Frame { id, path: child_path(&frame.path, name.as_str()), depth: frame.depth + 1, expanded: false });
+			}
+		}
+	}
+}
+
+impl<'a> Iterator for Walker<'a> {
+	type Item = (String, usize, &'a DirectoryEntry);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(frame) = self.pending.take() {
+			self.push_children(&frame);
+		}
+		loop {
+			let mut frame = self.stack.pop()?;
+			let entry: &'a DirectoryEntry = &self.cfb.dirs[frame.id];
+			match self.order {
+				WalkOrder::PreOrder => {
+					if is_storage(entry) {
+						self.pending = Some(frame.clone());
+					}
+					return Some((frame.path, frame.depth, entry));
+				}
+				WalkOrder::PostOrder => {
+					if frame.expanded || !is_storage(entry) {
+						return Some((frame.path, frame.depth, entry));
+					}
+					frame.expanded = true;
+					self.stack.push(frame.clone());
+					self.push_children(&frame);
+				}
+			}
+		}
+	}
+}
+
+// Callbacks for CompoundFile::visit. Every method does nothing by default.
+pub trait Visitor {
+	// Called before the children of a storage, including the root storage.
+	// Returning false skips its children.
+	fn enter_storage(&mut self, _path: &str, _entry: &DirectoryEntry) -> bool {
+		true
+	}
+
+	// Called after the children of a storage, even if they were skipped.
+	fn leave_storage(&mut self, _path: &str, _entry: &DirectoryEntry) {}
+
+	fn visit_stream(&mut self, _path: &str, _entry: &DirectoryEntry) {}
+}
+
+// Walks the directory tree of cfb depth first, calling visitor for every
+// storage and stream. Entries of other object types are passed over.
+pub fn visit<V: Visitor + ?Sized>(cfb: &CompoundFile, visitor: &mut V) {
+	let mut walker = Walker::new(cfb, WalkOrder::PreOrder);
+	// storages entered but not left yet, innermost last
+	let mut open: Vec<(String, usize, &DirectoryEntry)> = Vec::new();
+	while let Some((path, depth, entry)) = walker.next() {
+		while open.last().is_some_and(|&(_, open_depth, _)| open_depth >= depth) {
+			if let Some((path, _, entry)) = open.pop() {
+				visitor.leave_storage(&path, entry);
+			}
+		}
+		if is_storage(entry) {
+			if !visitor.enter_storage(&path, entry) {
+				walker.skip_subtree();
+			}
+			open.push((path, depth, entry));
+		} else if entry.object_type == dir::OBJECT_STREAM {
+			visitor.visit_stream(&path, entry);
+		}
+	}
+	while let Some((path, _, entry)) = open.pop() {
+		visitor.leave_storage(&path, entry);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::builder::CompoundFileBuilder;
+
+	fn sample() -> CompoundFile {
+		let mut builder = CompoundFileBuilder::new_v3();
+		builder.create_storage("/A").unwrap()
+			.create_storage("/A/B").unwrap()
+			.create_stream("/A/B/y", vec![1]).unwrap()
+			.create_stream("/A/x", vec![2]).unwrap()
+			.create_stream("/z", vec![3]).unwrap();
+		builder.build().unwrap()
+	}
+
+	fn paths(walker: Walker<'_>) -> Vec<(String, usize)> {
+		walker.map(|(path, depth, _)| (path, depth)).collect()
+	}
+
+	fn expected(paths: &[(&str, usize)]) -> Vec<(String, usize)> {
+		paths.iter().map(|&(path, depth)| (path.to_string(), depth)).collect()
+	}
+
+	#[test]
+	fn pre_order_yields_storages_before_children() {
+		let cfb = sample();
+		assert_eq!(paths(cfb.walk(WalkOrder::PreOrder)), expected(&[("/", 0), ("/A", 1), ("/A/B", 2), ("/A/B/y", 3), ("/A/x", 2), ("/z", 1)]));
+	}
+
+	#[test]
+	fn post_order_yields_storages_after_children() {
+		let cfb = sample();
+		assert_eq!(paths(cfb.walk(WalkOrder::PostOrder)), expected(&[("/A/B/y", 3), ("/A/B", 2), ("/A/x", 2), ("/A", 1), ("/z", 1), ("/", 0)]));
+	}
+
+	#[test]
+	fn skip_subtree_skips_children_of_last_storage() {
+		let cfb = sample();
+		let mut walker = cfb.walk(WalkOrder::PreOrder);
+		let mut visited = Vec::new();
+		while let Some((path, _, _)) = walker.next() {
+			if path == "/A" {
+				walker.skip_subtree();
+			}
+			visited.push(path);
+		}
+		assert_eq!(visited, ["/", "/A", "/z"]);
+	}
+
+	#[derive(Default)]
+	struct Recorder {
+		events: Vec<String>,
+	}
+
+	impl Visitor for Recorder {
+		fn enter_storage(&mut self, path: &str, _entry: &DirectoryEntry) -> bool {
+			self.events.push(format!("enter {}", path));
+			path != "/A/B"
+		}
+
+		fn leave_storage(&mut self, path: &str, _entry: &DirectoryEntry) {
+			self.events.push(format!("leave {}", path));
+		}
+
+		fn visit_stream(&mut self, path: &str, _entry: &DirectoryEntry) {
+			self.events.push(format!("stream {}", path));
+		}
+	}
+
+	#[test]
+	fn visitor_callbacks_are_nested() {
+		let cfb = sample();
+		let mut recorder = Recorder::default();
+		cfb.visit(&mut recorder);
+		// /A/B is left even though entering it returned false
+		assert_eq!(recorder.events, [
+			"enter /", "enter /A", "enter /A/B", "leave /A/B", "stream /A/x", "leave /A", "stream /z", "leave /",
+		]);
+	}
+}